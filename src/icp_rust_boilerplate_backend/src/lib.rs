@@ -1,35 +1,274 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Principal};
+#[cfg(not(test))]
+use ic_cdk::api::{caller, is_controller, print, time};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Blob;
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
 use std::{borrow::Cow, cell::RefCell};
+#[cfg(test)]
+use test_env::{caller, is_controller, print, time};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type PrincipalKey = Blob<29>;  // Principals are at most 29 bytes long
+type StatusKey = Blob<64>;  // Statuses named in transition rules
+type OriginKey = Blob<64>;  // Origins on the allowlist
+type AuthorityKey = Blob<64>;  // Names of certification authorities
 
+// Upper bound for the `limit` accepted by paginated queries, unless an admin
+// configured another one within `PAGE_SIZE_RANGE`
+const DEFAULT_MAX_PAGE_SIZE: u64 = 100;
+const PAGE_SIZE_RANGE: std::ops::RangeInclusive<u64> = 1..=1000;
+
+// Shortest text query accepted by the search endpoints, in characters, unless
+// an admin configured another one
+const DEFAULT_MIN_SEARCH_LENGTH: u64 = 2;
+
+// Largest edit distance `search_products_fuzzy` accepts, to bound its cost
+const MAX_FUZZY_DISTANCE: u32 = 3;
+
+// Most buckets `creation_histogram` returns
+const MAX_HISTOGRAM_BUCKETS: u64 = 1000;
+
+// Products per chunk of a snapshot export, and how long a snapshot is kept
+const EXPORT_CHUNK_SIZE: u64 = 200;
+const EXPORT_TTL_NS: u64 = 60 * 60 * 1_000_000_000;
+
+// Longest accepted lot id, in characters
+const MAX_LOT_ID_LEN: usize = 64;
+
+// Deepest allowed nesting of products (unit -> box -> pallet -> ...)
+const MAX_HIERARCHY_DEPTH: usize = 32;
+
+// Longest accepted tracking code, in characters
+const MAX_TRACKING_CODE_LEN: usize = 32;
+
+// Longest accepted document name, in characters
+const MAX_DOCUMENT_NAME_LEN: usize = 100;
+
+// Photos anchored per product, and the longest caption in characters
+const MAX_PHOTOS_PER_PRODUCT: u64 = 20;
+const MAX_PHOTO_CAPTION_LEN: usize = 200;
+
+// Most status events a new product can bring along in `initial_history`
+const MAX_INITIAL_HISTORY_LEN: usize = 100;
+
+// Longest accepted tag, in characters
+const MAX_TAG_LEN: usize = 32;
+
+// Longest accepted metadata key in characters, and the most bytes all of a
+// product's metadata keys and values may take together
+const MAX_METADATA_KEY_LEN: usize = 32;
+const MAX_METADATA_BYTES: usize = 512;
+
+// Longest accepted note, in characters
+const MAX_NOTE_LEN: usize = 500;
+
+// Audit event details are truncated to this many bytes
+const MAX_AUDIT_DETAIL_LEN: usize = 512;
+
+// Version of the stored record layout, bumped whenever `Product` or
+// `StatusEvent` fields change. Version 1 stored statuses as free text;
+// version 2 had no `TIMESTAMP_INDEX`.
+const SCHEMA_VERSION: u32 = 3;
+
+// Number of recent (seq, product id) changes kept for
+// `changed_product_ids_since`. Clients further behind must resync fully.
+const CHANGE_RING_SIZE: u64 = 1024;
+
+// Audit actions that count as a product changing hands
+const CUSTODY_ACTIONS: [&str; 2] = ["custodian_changed", "owner_assigned"];
+
+// Accepted product priorities, 1 being the most urgent
+const PRIORITY_RANGE: std::ops::RangeInclusive<u8> = 1..=5;
+
+// Products shown in the dashboard's "latest" widget
+const DASHBOARD_LATEST: usize = 5;
+
+// Custom status marking recalled products
+const RECALLED_STATUS: &str = "Recalled";
+
+// Label that status breakdowns group blank statuses under
+const UNKNOWN_STATUS: &str = "(unknown)";
+
+// Status of the placeholder `get_product_or_default` returns for missing ids
+const NOT_FOUND_STATUS: &str = "(not found)";
+
+// Mean Earth radius used for distances
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+// Columns of the CSV export, in order
+const CSV_HEADER: &str = "id,name,origin,current_location,status,timestamp,last_update";
+
+// Where a product is in its lifecycle. Anything outside the common stages
+// goes in `Custom`.
+#[derive(
+    candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug,
+)]
+enum ProductStatus {
+    Manufactured,
+    InTransit,
+    Delivered,
+    Returned,
+    Custom(String),
+}
+
+// An empty `Custom` status, which `add_product` replaces with the configured
+// default
+impl Default for ProductStatus {
+    fn default() -> Self {
+        ProductStatus::Custom(String::new())
+    }
+}
+
+impl ProductStatus {
+    // Parses free text, as stored before statuses were typed. Common stages
+    // are recognised regardless of case and separators ("in transit",
+    // "In-Transit"); anything else becomes `Custom`.
+    fn parse(text: &str) -> Self {
+        let normalized: String = text
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect();
+        match normalized.as_str() {
+            "manufactured" => ProductStatus::Manufactured,
+            "intransit" => ProductStatus::InTransit,
+            "delivered" => ProductStatus::Delivered,
+            "returned" => ProductStatus::Returned,
+            _ => ProductStatus::Custom(text.to_string()),
+        }
+    }
+
+    // A `Custom` status spelling out a common stage becomes that stage
+    fn canonical(self) -> Self {
+        match self {
+            ProductStatus::Custom(text) => ProductStatus::parse(&text),
+            status => status,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            ProductStatus::Manufactured => "Manufactured",
+            ProductStatus::InTransit => "InTransit",
+            ProductStatus::Delivered => "Delivered",
+            ProductStatus::Returned => "Returned",
+            ProductStatus::Custom(text) => text,
+        }
+    }
+
+    fn is_blank(&self) -> bool {
+        self.as_str().trim().is_empty()
+    }
+}
+
+impl std::fmt::Display for ProductStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Records stored before statuses were typed hold free text in `status`;
+// they decode as `Product<String>` and are converted on load.
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct Product {
+struct Product<S = ProductStatus> {
     id: u64,
     name: String,
     origin: String,
     current_location: String,
-    status: String,  // e.g., "Manufactured", "In Transit", "Delivered"
+    status: S,
     certification: Option<String>,
     timestamp: u64,
     last_update: Option<u64>,
     iot_data: Option<String>,  // Data from IoT sensors
+    // Fields added after the initial release are optional so that records
+    // already in stable memory keep decoding.
+    created_by: Option<Principal>,
+    owner: Option<Principal>,
+    archived_at: Option<u64>,  // Set while the product is archived
+    lot_id: Option<String>,
+    custodian: Option<Principal>,  // Who physically holds the product, e.g. a carrier
+    weight_grams: Option<u64>,
+    dimensions_mm: Option<(u32, u32, u32)>,  // Length, width, height
+    latitude: Option<f64>,  // Degrees, set together with `longitude`
+    longitude: Option<f64>,
+    tracking_code: Option<String>,  // Customer-facing code, unique across products
+    parent_id: Option<u64>,  // Container holding this product, e.g. a pallet
+    estimated_delivery_ns: Option<u64>,  // ETA, never before `timestamp`
+    field_updated_at: Option<Vec<(String, u64)>>,  // Last change of each mutable field
+    enrichment: Option<String>,  // Supplementary data from the enrichment canister
+    priority: Option<u8>,  // 1 (highest) to 5
+    sla_deadline_ns: Option<u64>,  // Contractual deadline, never before `timestamp`
+    tags: Option<Vec<String>>,  // Labels for grouping, e.g. "priority-review"
+    declared_value_cents: Option<u64>,  // Insured value, in `currency`
+    currency: Option<String>,  // ISO 4217 code, e.g. "USD"
+    metadata: Option<Vec<(String, String)>>,  // Customer-defined fields, unique keys
+    cert_authority: Option<String>,  // Who issued `certification`
+    cert_verified: Option<bool>,  // Authority's verdict, cleared when either changes
+    cert_verified_at: Option<u64>,
+    cert_expires_at: Option<u64>,  // When `certification` lapses
+    is_test: Option<bool>,  // Sample data, left out of stats unless asked for
+    last_modified_by: Option<Principal>,  // Who made the latest change
 }
 
 // Implementing Storable for Product
 impl Storable for Product {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), Product<String>).map(Product::from))
+            .unwrap()
+    }
+}
+
+impl From<Product<String>> for Product {
+    fn from(legacy: Product<String>) -> Self {
+        Product {
+            id: legacy.id,
+            name: legacy.name,
+            origin: legacy.origin,
+            current_location: legacy.current_location,
+            status: ProductStatus::parse(&legacy.status),
+            certification: legacy.certification,
+            timestamp: legacy.timestamp,
+            last_update: legacy.last_update,
+            iot_data: legacy.iot_data,
+            created_by: legacy.created_by,
+            owner: legacy.owner,
+            archived_at: legacy.archived_at,
+            lot_id: legacy.lot_id,
+            custodian: legacy.custodian,
+            weight_grams: legacy.weight_grams,
+            dimensions_mm: legacy.dimensions_mm,
+            latitude: legacy.latitude,
+            longitude: legacy.longitude,
+            tracking_code: legacy.tracking_code,
+            parent_id: legacy.parent_id,
+            estimated_delivery_ns: legacy.estimated_delivery_ns,
+            field_updated_at: legacy.field_updated_at,
+            enrichment: legacy.enrichment,
+            priority: legacy.priority,
+            sla_deadline_ns: legacy.sla_deadline_ns,
+            tags: legacy.tags,
+            declared_value_cents: legacy.declared_value_cents,
+            currency: legacy.currency,
+            metadata: legacy.metadata,
+            cert_authority: legacy.cert_authority,
+            cert_verified: legacy.cert_verified,
+            cert_verified_at: legacy.cert_verified_at,
+            cert_expires_at: legacy.cert_expires_at,
+            is_test: legacy.is_test,
+            last_modified_by: legacy.last_modified_by,
+        }
     }
 }
 
@@ -39,30 +278,539 @@ impl BoundedStorable for Product {
     const IS_FIXED_SIZE: bool = false;
 }
 
+// A stored product's bytes exactly as they are in stable memory, undecoded
+struct RawProduct(Vec<u8>);
+
+impl Storable for RawProduct {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawProduct(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for RawProduct {
+    const MAX_SIZE: u32 = Product::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = Product::IS_FIXED_SIZE;
+}
+
+// A status change recorded in a product's history
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StatusEvent<S = ProductStatus> {
+    status: S,
+    location: String,  // Where the product was when the status changed
+    timestamp: u64,
+}
+
+impl Storable for StatusEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), StatusEvent<String>).map(StatusEvent::from))
+            .unwrap()
+    }
+}
+
+impl From<StatusEvent<String>> for StatusEvent {
+    fn from(legacy: StatusEvent<String>) -> Self {
+        StatusEvent {
+            status: ProductStatus::parse(&legacy.status),
+            location: legacy.location,
+            timestamp: legacy.timestamp,
+        }
+    }
+}
+
+// Status and location are copied from a product, so its bound is enough
+impl BoundedStorable for StatusEvent {
+    const MAX_SIZE: u32 = Product::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// An entry in the audit log. `seq` increases by one for every event.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AuditEvent {
+    seq: u64,
+    product_id: u64,
+    action: String,  // e.g., "created", "updated", "lot_changed"
+    caller: Principal,
+    timestamp: u64,
+    detail: String,
+}
+
+impl Storable for AuditEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// `record_audit` truncates `detail`, which keeps events within this bound
+impl BoundedStorable for AuditEvent {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Reference to an off-chain document, anchored by its SHA-256 hash
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DocumentRef {
+    name: String,
+    sha256_hex: String,  // Lowercase
+    added_at: u64,
+    added_by: Principal,
+}
+
+impl Storable for DocumentRef {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Fits a name of `MAX_DOCUMENT_NAME_LEN` characters
+impl BoundedStorable for DocumentRef {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Reference to an off-chain photo (e.g., of damaged goods), anchored by its
+// SHA-256 hash
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PhotoRef {
+    sha256_hex: String,  // Lowercase
+    caption: Option<String>,
+    added_at: u64,
+}
+
+impl Storable for PhotoRef {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Fits a caption of `MAX_PHOTO_CAPTION_LEN` characters
+impl BoundedStorable for PhotoRef {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A free-text note left on a product by an operator
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Note {
+    text: String,
+    author: Principal,
+    added_at: u64,
+}
+
+impl Storable for Note {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Fits a note of `MAX_NOTE_LEN` characters
+impl BoundedStorable for Note {
+    const MAX_SIZE: u32 = 2560;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// One entry of a product's activity feed
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TimelineEntry {
+    kind: String,  // "status", "note" or "audit"
+    timestamp: u64,
+    detail: String,
+}
+
+// Canister-wide settings managed by admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Config {
+    max_products_per_owner: Option<u64>,  // `None` means unlimited
+    default_status: Option<String>,  // Used by `add_product` when no status is given
+    max_page_size: Option<u64>,  // `None` means `DEFAULT_MAX_PAGE_SIZE`
+    auto_archive_after_ns: Option<u64>,  // `None` disables `run_auto_archive`
+    auto_archive_interval_secs: Option<u64>,  // `None` means no periodic run
+    registry_canister: Option<Principal>,  // Told about every new product
+    enrichment_canister: Option<Principal>,  // Queried by `enrich_product`
+    min_search_length: Option<u64>,  // `None` means `DEFAULT_MIN_SEARCH_LENGTH`
+    read_only: Option<bool>,  // Set during maintenance to reject updates
+    terminal_statuses: Option<Vec<String>>,  // `None` means just "Delivered"
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Every `MemoryId` in use, in one place so none is handed out twice. Ids are
+// never reused, even after a storage is dropped, since old data may remain.
+mod memory_ids {
+    pub const ID_COUNTER: u8 = 0;
+    pub const PRODUCTS: u8 = 1;
+    pub const STATUS_HISTORY: u8 = 2;
+    pub const CONFIG: u8 = 3;
+    pub const OWNER_COUNTS: u8 = 4;
+    pub const EXPORT_TOKENS: u8 = 5;
+    pub const EXPORT_SNAPSHOTS: u8 = 6;
+    pub const AUDIT_LOG: u8 = 7;
+    pub const DOCUMENTS: u8 = 8;
+    pub const TRANSITIONS: u8 = 9;
+    pub const CHANGE_SEQ: u8 = 10;
+    pub const CHANGE_RING: u8 = 11;
+    pub const REGISTRY_NOTIFY_FAILURES: u8 = 12;
+    pub const SCHEMA_VERSION: u8 = 13;
+    pub const RESERVATIONS: u8 = 14;
+    pub const PURGED: u8 = 15;
+    pub const PHOTOS: u8 = 16;
+    pub const NOTES: u8 = 17;
+    pub const UNDO_SNAPSHOTS: u8 = 18;
+    pub const ALLOWED_ORIGINS: u8 = 19;
+    pub const CERT_AUTHORITIES: u8 = 20;
+    pub const TIMESTAMP_INDEX: u8 = 21;
+
+    // What each id holds, for `memory_layout`. Every id above must be listed.
+    pub const LAYOUT: [(u8, &str); 22] = [
+        (ID_COUNTER, "Next product id"),
+        (PRODUCTS, "Products by id"),
+        (STATUS_HISTORY, "Status changes per product"),
+        (CONFIG, "Canister-wide settings"),
+        (OWNER_COUNTS, "Non-archived products per owner"),
+        (EXPORT_TOKENS, "Export snapshot tokens"),
+        (EXPORT_SNAPSHOTS, "Product ids captured by export snapshots"),
+        (AUDIT_LOG, "Audit events by sequence number"),
+        (DOCUMENTS, "Documents anchored per product"),
+        (TRANSITIONS, "Allowed status transitions"),
+        (CHANGE_SEQ, "Change sequence number"),
+        (CHANGE_RING, "Recent changes for polling clients"),
+        (REGISTRY_NOTIFY_FAILURES, "Failed registry notifications"),
+        (SCHEMA_VERSION, "Schema version of the stored records"),
+        (RESERVATIONS, "Reserved product ids"),
+        (PURGED, "Deleted product ids"),
+        (PHOTOS, "Photos anchored per product"),
+        (NOTES, "Notes per product"),
+        (UNDO_SNAPSHOTS, "Products before their latest update"),
+        (ALLOWED_ORIGINS, "Origins new products may name"),
+        (CERT_AUTHORITIES, "Canisters of certification authorities"),
+        (TIMESTAMP_INDEX, "Product ids in creation order"),
+    ];
+
+    // Fails the build if an id appears twice in `LAYOUT`, or out of order so
+    // the next free id is always one past the last entry
+    const _: () = {
+        let mut i = 0;
+        while i < LAYOUT.len() {
+            let mut j = i + 1;
+            while j < LAYOUT.len() {
+                assert!(LAYOUT[i].0 != LAYOUT[j].0, "MemoryId used twice");
+                j += 1;
+            }
+            assert!(LAYOUT[i].0 as usize == i, "MemoryId out of order in LAYOUT");
+            i += 1;
+        }
+    };
+}
+
+// The virtual memory behind `id`, which must come from `memory_ids`
+fn memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
     );
 
     static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+        IdCell::init(memory(memory_ids::ID_COUNTER), 0)
             .expect("Cannot create an ID counter")
     );
 
     static PRODUCT_STORAGE: RefCell<StableBTreeMap<u64, Product, Memory>> =
         RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+            memory(memory_ids::PRODUCTS)
+    ));
+
+    // Keyed by (product id, position in that product's history)
+    static STATUS_HISTORY: RefCell<StableBTreeMap<(u64, u64), StatusEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::STATUS_HISTORY)
+    ));
+
+    static CONFIG: RefCell<Cell<Config, Memory>> = RefCell::new(
+        Cell::init(memory(memory_ids::CONFIG), Config::default())
+            .expect("Cannot create the config cell")
+    );
+
+    // Number of non-archived products per owner, kept in sync by `do_insert`
+    // and `delete_product`
+    static OWNER_COUNTS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::OWNER_COUNTS)
+    ));
+
+    // Export token -> creation time of its snapshot
+    static EXPORT_TOKENS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::EXPORT_TOKENS)
+    ));
+
+    // (export token, position) -> product id captured by the snapshot
+    static EXPORT_SNAPSHOTS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::EXPORT_SNAPSHOTS)
+    ));
+
+    // Keyed by sequence number, starting at 1
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::AUDIT_LOG)
+    ));
+
+    // Keyed by (product id, position). Kept out of `Product` so attaching
+    // paperwork can't push a record past its size limit.
+    static DOCUMENTS: RefCell<StableBTreeMap<(u64, u64), DocumentRef, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::DOCUMENTS)
+    ));
+
+    // Allowed (from, to) status transitions. While empty, any transition is
+    // allowed.
+    static TRANSITIONS: RefCell<StableBTreeMap<(StatusKey, StatusKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::TRANSITIONS)
+    ));
+
+    // Incremented on every mutation of a product
+    static CHANGE_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(memory(memory_ids::CHANGE_SEQ), 0)
+            .expect("Cannot create the change sequence")
+    );
+
+    // Ring buffer of recent changes: slot (seq % CHANGE_RING_SIZE) ->
+    // (seq, product id)
+    static CHANGE_RING: RefCell<StableBTreeMap<u64, (u64, u64), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::CHANGE_RING)
+    ));
+
+    // Registry notifications that could not be sent
+    static REGISTRY_NOTIFY_FAILURES: RefCell<IdCell> = RefCell::new(
+        IdCell::init(memory(memory_ids::REGISTRY_NOTIFY_FAILURES), 0)
+            .expect("Cannot create the registry failure counter")
+    );
+
+    // Schema version of the stored records. Canisters that predate this cell
+    // start at 1; fresh installs are set to `SCHEMA_VERSION` by `init`.
+    static STORED_SCHEMA_VERSION: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(memory(memory_ids::SCHEMA_VERSION), 1)
+            .expect("Cannot create the schema version cell")
+    );
+
+    // Ids taken by `reserve_product_id` and not finalized yet -> who took them
+    static RESERVATIONS: RefCell<StableBTreeMap<u64, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::RESERVATIONS)
+    ));
+
+    // Deleted product id -> when it was deleted. Ids are never reused, so an
+    // entry stays valid forever.
+    static PURGED: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::PURGED)
+    ));
+
+    // Keyed by (product id, position), like `DOCUMENTS`
+    static PHOTOS: RefCell<StableBTreeMap<(u64, u64), PhotoRef, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::PHOTOS)
+    ));
+
+    // Keyed by (product id, position), like `DOCUMENTS`
+    static NOTES: RefCell<StableBTreeMap<(u64, u64), Note, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::NOTES)
+    ));
+
+    // Each product as it was before its latest `update_product` or
+    // `patch_product`, for `undo_last_update`
+    static UNDO_SNAPSHOTS: RefCell<StableBTreeMap<u64, Product, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::UNDO_SNAPSHOTS)
+    ));
+
+    // Once any origin is listed, products may only name listed origins
+    static ALLOWED_ORIGINS: RefCell<StableBTreeMap<OriginKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::ALLOWED_ORIGINS)
+    ));
+
+    // Certification authority name -> its canister, for `verify_certification`
+    static CERT_AUTHORITIES: RefCell<StableBTreeMap<AuthorityKey, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::CERT_AUTHORITIES)
+    ));
+
+    // (creation timestamp, id) of every product, for time-ordered queries.
+    // Kept in sync by `do_insert` and `remove_product`, and backfilled by
+    // `migrate_products`; the queries reading it fail until then.
+    static TIMESTAMP_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            memory(memory_ids::TIMESTAMP_INDEX)
     ));
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct ProductPayload {
     name: String,
     origin: String,
     current_location: String,
-    status: String,
+    status: ProductStatus,
     certification: Option<String>,
     iot_data: Option<String>,  // IoT data can be supplied here
+    weight_grams: Option<u64>,
+    dimensions_mm: Option<(u32, u32, u32)>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    tracking_code: Option<String>,  // Only used on creation
+    estimated_delivery_ns: Option<u64>,
+    priority: Option<u8>,
+    sla_deadline_ns: Option<u64>,
+    declared_value_cents: Option<u64>,
+    currency: Option<String>,
+    cert_authority: Option<String>,
+    cert_expires_at: Option<u64>,
+    is_test: Option<bool>,  // Only used on creation
+    initial_history: Option<Vec<StatusEvent>>,  // Only used on creation, oldest first
+}
+
+// Criteria for `query_products` and friends; unset fields match everything
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ProductFilter {
+    status: Option<ProductStatus>,
+    origin: Option<String>,
+    current_location: Option<String>,  // Compared in canonical form
+    owner: Option<Principal>,
+    lot_id: Option<String>,
+    created_after: Option<u64>,  // Inclusive
+    created_before: Option<u64>,  // Exclusive
+    include_archived: Option<bool>,  // Archived products are skipped by default
+    include_test: Option<bool>,  // Test products are skipped by default
+}
+
+// Partial update for `patch_product`; only the fields that are set change
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ProductPatch {
+    name: Option<String>,
+    origin: Option<String>,
+    current_location: Option<String>,
+    status: Option<ProductStatus>,
+    certification: Option<String>,  // Use `clear_certification` to remove it
+    iot_data: Option<String>,
+    weight_grams: Option<u64>,
+    dimensions_mm: Option<(u32, u32, u32)>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    estimated_delivery_ns: Option<u64>,
+    priority: Option<u8>,
+    sla_deadline_ns: Option<u64>,
+    declared_value_cents: Option<u64>,
+    currency: Option<String>,
+    cert_authority: Option<String>,
+    cert_expires_at: Option<u64>,
+}
+
+// Filter results together with facet counts for a search sidebar
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FacetedResult {
+    items: Vec<Product>,
+    status_counts: Vec<(ProductStatus, u64)>,
+    origin_counts: Vec<(String, u64)>,
+}
+
+// One chunk of a snapshot export
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ExportChunk {
+    products: Vec<Product>,
+    has_more: bool,
+}
+
+// Creation/update time range covered by the stored products
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TimestampBounds {
+    earliest_created: u64,
+    latest_created: u64,
+    latest_update: u64,  // Most recent creation or update across all products
+}
+
+// A product past its SLA deadline and by how much
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SlaBreach {
+    product: Product,
+    overdue_ns: u64,
+}
+
+// A page of `list_by_timestamp_after`
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ProductPage {
+    items: Vec<Product>,
+    next: Option<(u64, u64)>,  // (timestamp, id) to continue after; `None` on the last page
+}
+
+// One status column of `kanban_view`
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct KanbanColumn {
+    status: ProductStatus,
+    total: u64,  // Every product in the status, not just those in `products`
+    products: Vec<Product>,  // Most recently created first
+}
+
+// Outcome of one patch in `patch_products_batch`
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PatchResult {
+    id: u64,
+    result: Result<Product, Error>,
+}
+
+// A field whose value differs between two products
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FieldDiff {
+    field: String,
+    a: String,
+    b: String,
+}
+
+// Everything the dashboard shows, computed from the same state
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DashboardSnapshot {
+    total: u64,
+    status_breakdown: Vec<(ProductStatus, u64)>,
+    latest_products: Vec<Product>,  // Most recently created first
+    overdue_count: u64,
+    recalled_count: u64,
 }
 
 // Query to retrieve a product by ID
@@ -76,65 +824,4351 @@ fn get_product(id: u64) -> Result<Product, Error> {
     }
 }
 
+// The product with `id`, or a placeholder for clients that render a skeleton
+// instead of handling an error. The placeholder is not a stored record: it
+// has the requested id, status "(not found)" and every other field empty.
+#[ic_cdk::query]
+fn get_product_or_default(id: u64) -> Product {
+    _get_product(&id).unwrap_or_else(|| Product {
+        id,
+        status: ProductStatus::Custom(NOT_FOUND_STATUS.to_string()),
+        ..Default::default()
+    })
+}
+
+// The bytes a product is stored as, for debugging decode failures (admin
+// only). Records written by older versions come back in their old layout,
+// since nothing is decoded.
+#[ic_cdk::query]
+fn get_product_raw_bytes(id: u64) -> Result<Vec<u8>, Error> {
+    ensure_admin()?;
+    match raw_products().get(&id) {
+        Some(RawProduct(bytes)) => Ok(bytes),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Helper method to read `PRODUCT_STORAGE` without decoding its values. The
+// map is initialized first, so this only ever loads an existing one.
+fn raw_products() -> StableBTreeMap<u64, RawProduct, Memory> {
+    PRODUCT_STORAGE.with(|storage| storage.borrow().len());
+    StableBTreeMap::load(memory(memory_ids::PRODUCTS))
+}
+
 // Add a new product entry
 #[ic_cdk::update]
-fn add_product(product: ProductPayload) -> Option<Product> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
-        
-    let product = Product {
-        id,
-        name: product.name,
-        origin: product.origin,
-        current_location: product.current_location,
-        status: product.status,
-        certification: product.certification,
-        timestamp: time(),
-        last_update: None,
-        iot_data: product.iot_data,
-    };
-    
-    do_insert(&product);
-    Some(product)
+fn add_product(mut product: ProductPayload) -> Result<Product, Error> {
+    ensure_writable()?;
+    check_new_product(&mut product)?;
+    let id = allocate_product_id()?;
+    create_product(id, product)
 }
 
-// Update product details (e.g., location, status, certification, IoT data)
+// Take the next product id without creating the product, e.g. to print its
+// label first. Only the caller (or an admin) can pass it to
+// `finalize_reserved`.
 #[ic_cdk::update]
-fn update_product(id: u64, payload: ProductPayload) -> Result<Product, Error> {
+fn reserve_product_id() -> Result<u64, Error> {
+    ensure_writable()?;
+    let id = allocate_product_id()?;
+    RESERVATIONS.with(|reservations| {
+        reservations
+            .borrow_mut()
+            .insert(id, principal_key(&caller()))
+    });
+    Ok(id)
+}
+
+// Create the product for an id taken with `reserve_product_id`
+#[ic_cdk::update]
+fn finalize_reserved(id: u64, mut payload: ProductPayload) -> Result<Product, Error> {
+    ensure_writable()?;
+    let reserved_by = match RESERVATIONS.with(|reservations| reservations.borrow().get(&id)) {
+        Some(key) => key,
+        None if PRODUCT_STORAGE.with(|storage| storage.borrow().contains_key(&id)) => {
+            return Err(Error::InvalidInput {
+                msg: format!("Product id={} was already finalized", id),
+            });
+        }
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Product id={} was never reserved", id),
+            });
+        }
+    };
+    if reserved_by != principal_key(&caller()) && !is_controller(&caller()) {
+        return Err(Error::Unauthorized {
+            msg: format!("Product id={} was reserved by someone else", id),
+        });
+    }
+    check_new_product(&mut payload)?;
+
+    let product = create_product(id, payload)?;
+    RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&id));
+    Ok(product)
+}
+
+// Check a payload without storing anything, so clients can validate a form
+// (or a batch) before submitting it. Runs the same checks as `add_product`
+// except the owner quota, since the payloads of a batch are checked one at a
+// time.
+#[ic_cdk::query]
+fn validate_payload(mut payload: ProductPayload) -> Result<(), Error> {
+    check_new_payload(&mut payload)
+}
+
+// List the caller's own products, a page at a time.
+// `offset` counts within the caller's products (in id order), not global ids.
+#[ic_cdk::query]
+fn get_my_products_paginated(offset: u64, limit: u64, include_test: Option<bool>) -> Vec<Product> {
+    let me = caller();
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.created_by == Some(me) && is_included(product, include_test))
+            .skip(offset as usize)
+            .take(page_limit(limit))
+            .collect()
+    })
+}
+
+// Products the caller may modify: the ones they own, or every product for an
+// admin. Uses the same check as `ensure_owner_or_admin`, so the UI offers
+// editing exactly where updates are allowed.
+#[ic_cdk::query]
+fn my_editable_products(include_test: Option<bool>) -> Vec<Product> {
+    let me = caller();
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| can_modify(product, &me) && is_included(product, include_test))
+            .collect()
+    })
+}
+
+// Products whose latest change was made by `principal`, e.g. for an
+// activity-by-user report. Products not changed since this was tracked are
+// left out.
+#[ic_cdk::query]
+fn products_last_modified_by(principal: Principal, include_test: Option<bool>) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product.last_modified_by == Some(principal) && is_included(product, include_test)
+            })
+            .collect()
+    })
+}
+
+// Products created by `creator` in [`start_ns`, `end_ns`), e.g. for a
+// per-supplier activity report over a billing period
+#[ic_cdk::query]
+fn products_by_creator_between(
+    creator: Principal,
+    start_ns: u64,
+    end_ns: u64,
+    include_test: Option<bool>,
+) -> Result<Vec<Product>, Error> {
+    ensure_migrated()?;
+    if start_ns > end_ns {
+        return Err(Error::InvalidInput {
+            msg: "Start must not be after end".to_string(),
+        });
+    }
+    let ids: Vec<u64> = TIMESTAMP_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((start_ns, 0)..(end_ns, 0))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    Ok(ids
+        .iter()
+        .filter_map(_get_product)
+        .filter(|product| product.created_by == Some(creator) && is_included(product, include_test))
+        .collect())
+}
+
+// Non-archived products that have been in transit for more than
+// `max_transit_ns` at `now_ns`, counted from when their history shows them
+// entering transit, or from their last update when it does not
+#[ic_cdk::query]
+fn stuck_in_transit(max_transit_ns: u64, now_ns: u64, include_test: Option<bool>) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product.status == ProductStatus::InTransit
+                    && product.archived_at.is_none()
+                    && is_included(product, include_test)
+            })
+            .filter(|product| {
+                let since = transit_started_at(product)
+                    .unwrap_or(product.last_update.unwrap_or(product.timestamp));
+                now_ns.saturating_sub(since) > max_transit_ns
+            })
+            .collect()
+    })
+}
+
+// Products in creation order, oldest first with ties broken by id, a page at
+// a time: pass the `next` cursor of the previous page as `after_ts` and
+// `after_id`, or nothing to start. With only `after_ts`, the page starts
+// after every product created at that time.
+#[ic_cdk::query]
+fn list_by_timestamp_after(
+    after_ts: Option<u64>,
+    after_id: Option<u64>,
+    limit: u64,
+    include_test: Option<bool>,
+) -> Result<ProductPage, Error> {
+    ensure_migrated()?;
+    let start = match after_ts {
+        Some(ts) => Bound::Excluded((ts, after_id.unwrap_or(u64::MAX))),
+        None => Bound::Unbounded,
+    };
+    let limit = page_limit(limit);
+    let mut products: Vec<Product> = TIMESTAMP_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .filter_map(|((_, id), _)| _get_product(&id))
+            .filter(|product| is_included(product, include_test))
+            .take(limit.saturating_add(1))
+            .collect()
+    });
+    let more = products.len() > limit;
+    products.truncate(limit);
+    let next = match products.last() {
+        Some(last) if more => Some((last.timestamp, last.id)),
+        _ => None,
+    };
+    Ok(ProductPage {
+        items: products,
+        next,
+    })
+}
+
+// Whether `TIMESTAMP_INDEX` lists exactly the stored products, each under
+// its creation time
+#[ic_cdk::query]
+fn verify_timestamp_index() -> bool {
+    let expected: BTreeSet<(u64, u64)> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(id, product)| (product.timestamp, id))
+            .collect()
+    });
+    let indexed: BTreeSet<(u64, u64)> =
+        TIMESTAMP_INDEX.with(|index| index.borrow().iter().map(|(key, _)| key).collect());
+    expected == indexed
+}
+
+// Time range spanned by the stored products, or `None` when there are none.
+// Test products only count with `include_test`, as in every aggregate.
+#[ic_cdk::query]
+fn timestamp_bounds(include_test: Option<bool>) -> Option<TimestampBounds> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, product)| is_included(product, include_test))
+            .fold(None, |bounds: Option<TimestampBounds>, (_, product)| {
+                let updated = product.last_update.unwrap_or(product.timestamp);
+                Some(match bounds {
+                    None => TimestampBounds {
+                        earliest_created: product.timestamp,
+                        latest_created: product.timestamp,
+                        latest_update: updated,
+                    },
+                    Some(b) => TimestampBounds {
+                        earliest_created: b.earliest_created.min(product.timestamp),
+                        latest_created: b.latest_created.max(product.timestamp),
+                        latest_update: b.latest_update.max(updated),
+                    },
+                })
+            })
+    })
+}
+
+// Status changes of a product, oldest first
+#[ic_cdk::query]
+fn get_status_history(id: u64) -> Result<Vec<StatusEvent>, Error> {
+    match _get_product(&id) {
+        Some(_) => Ok(get_history(id)),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Whether a product's status history is consistent: see
+// `history_problems` for what is checked
+#[ic_cdk::query]
+fn verify_history_consistency(id: u64) -> Result<bool, Error> {
+    history_problems(id).map(|problems| problems.is_empty())
+}
+
+// Inconsistencies in a product's status history: timestamps going backwards,
+// or a last entry that does not match the current status (a mutation that
+// forgot to record history). Empty when the history is consistent.
+#[ic_cdk::query]
+fn history_problems(id: u64) -> Result<Vec<String>, Error> {
+    let product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Product with id={} not found", id),
+    })?;
+    let history = get_history(id);
+
+    let mut problems = Vec::new();
+    for (position, pair) in history.windows(2).enumerate() {
+        if pair[1].timestamp < pair[0].timestamp {
+            problems.push(format!(
+                "Entry {} ({}) is older than entry {} ({})",
+                position + 1,
+                pair[1].timestamp,
+                position,
+                pair[0].timestamp
+            ));
+        }
+    }
+    match history.last() {
+        Some(last) if last.status != product.status => problems.push(format!(
+            "Last entry has status '{}' but the product is '{}'",
+            last.status, product.status
+        )),
+        Some(_) => {}
+        None => problems.push("History is empty".to_string()),
+    }
+    Ok(problems)
+}
+
+// Products that entered `status` at or after `since_ns`, regardless of their
+// current status
+#[ic_cdk::query]
+fn products_transitioned_to(
+    status: ProductStatus,
+    since_ns: u64,
+    include_test: Option<bool>,
+) -> Vec<Product> {
+    let status = status.canonical();
+    let ids: BTreeSet<u64> = STATUS_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter()
+            .filter(|(_, event)| event.status == status && event.timestamp >= since_ns)
+            .map(|((id, _), _)| id)
+            .collect()
+    });
+    ids.iter()
+        .filter_map(_get_product)
+        .filter(|product| is_included(product, include_test))
+        .collect()
+}
+
+// Average time (ns) products spend in `status`, or `None` if no product has
+// left it yet. Per product, every completed stay (from entering `status` to
+// the next status change) is summed; the sums are then averaged across
+// products. A stay in the product's current status is not counted.
+#[ic_cdk::query]
+fn average_time_in_status(status: ProductStatus, include_test: Option<bool>) -> Option<u64> {
+    let status = status.canonical();
+    let excluded = if include_test.unwrap_or(false) {
+        BTreeSet::new()
+    } else {
+        test_product_ids()
+    };
+    let mut per_product: BTreeMap<u64, u128> = Default::default();
+    STATUS_HISTORY.with(|history| {
+        let mut previous: Option<(u64, StatusEvent)> = None;
+        for ((id, _), event) in history.borrow().iter() {
+            if excluded.contains(&id) {
+                continue;
+            }
+            if let Some((prev_id, prev)) = &previous {
+                if *prev_id == id && prev.status == status {
+                    let spent = event.timestamp.saturating_sub(prev.timestamp) as u128;
+                    *per_product.entry(id).or_default() += spent;
+                }
+            }
+            previous = Some((id, event));
+        }
+    });
+    if per_product.is_empty() {
+        return None;
+    }
+    let total: u128 = per_product.values().sum();
+    Some((total / per_product.len() as u128) as u64)
+}
+
+// Every distinct (from, to) status change found in the products' histories,
+// with how often it happened, whether or not the transition is configured
+#[ic_cdk::query]
+fn observed_transitions(include_test: Option<bool>) -> Vec<(ProductStatus, ProductStatus, u64)> {
+    let excluded = if include_test.unwrap_or(false) {
+        BTreeSet::new()
+    } else {
+        test_product_ids()
+    };
+    let mut counts: BTreeMap<(ProductStatus, ProductStatus), u64> = BTreeMap::new();
+    STATUS_HISTORY.with(|history| {
+        let mut previous: Option<(u64, StatusEvent)> = None;
+        for ((id, _), event) in history.borrow().iter() {
+            if excluded.contains(&id) {
+                continue;
+            }
+            if let Some((prev_id, prev)) = previous {
+                if prev_id == id {
+                    *counts
+                        .entry((prev.status, event.status.clone()))
+                        .or_default() += 1;
+                }
+            }
+            previous = Some((id, event));
+        }
+    });
+    counts
+        .into_iter()
+        .map(|((from, to), count)| (from, to, count))
+        .collect()
+}
+
+// Undelivered products with priority 1 or 2, most urgent first and oldest
+// first within a priority, for an expedite queue. Archived products are left
+// out.
+#[ic_cdk::query]
+fn high_priority_undelivered(include_test: Option<bool>) -> Vec<Product> {
+    let mut products: Vec<Product> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                is_included(product, include_test)
+                    && product.archived_at.is_none()
+                    && product.status != ProductStatus::Delivered
+                    && product.priority.is_some_and(|priority| priority <= 2)
+            })
+            .collect()
+    });
+    products.sort_by_key(|product| (product.priority, product.timestamp, product.id));
+    products
+}
+
+// Products whose status is blank, which breakdowns count as "(unknown)", so
+// they can be cleaned up
+#[ic_cdk::query]
+fn products_with_unknown_status() -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.status.is_blank())
+            .collect()
+    })
+}
+
+// Number of products for every (origin, status) pair that occurs, e.g. for a
+// supplier heatmap
+#[ic_cdk::query]
+fn count_by_origin_and_status(include_test: Option<bool>) -> Vec<(String, ProductStatus, u64)> {
+    let mut counts: BTreeMap<(String, ProductStatus), u64> = BTreeMap::new();
+    PRODUCT_STORAGE.with(|storage| {
+        for (_, product) in storage.borrow().iter() {
+            if !is_included(&product, include_test) {
+                continue;
+            }
+            let status = status_bucket(&product.status);
+            *counts.entry((product.origin, status)).or_default() += 1;
+        }
+    });
+    counts
+        .into_iter()
+        .map(|((origin, status), count)| (origin, status, count))
+        .collect()
+}
+
+// Sorted distinct values of `field` ("status", "origin" or
+// "current_location") across all products, e.g. for filter dropdowns.
+// Locations that only differ in case or spacing count once, shown as spelled
+// by the oldest product.
+#[ic_cdk::query]
+fn distinct_values(field: String, include_test: Option<bool>) -> Result<Vec<String>, Error> {
+    let value: fn(Product) -> String = match field.as_str() {
+        "status" => |product| status_bucket(&product.status).to_string(),
+        "origin" => |product| product.origin,
+        "current_location" => |product| product.current_location,
+        _ => {
+            return Err(Error::InvalidInput {
+                msg: format!("Unknown field '{}'", field),
+            })
+        }
+    };
+    let mut values: BTreeMap<String, String> = BTreeMap::new();
+    PRODUCT_STORAGE.with(|storage| {
+        for (_, product) in storage.borrow().iter() {
+            if !is_included(&product, include_test) {
+                continue;
+            }
+            let value = value(product);
+            let key = if field == "current_location" {
+                normalize_location(&value)
+            } else {
+                value.clone()
+            };
+            values.entry(key).or_insert(value);
+        }
+    });
+    let mut values: Vec<String> = values.into_values().collect();
+    values.sort();
+    Ok(values)
+}
+
+// The form locations are compared and grouped in: trimmed, lowercase, with
+// runs of whitespace collapsed to one space. Stored locations keep the
+// spelling they were given.
+#[ic_cdk::query]
+fn canonical_location(input: String) -> String {
+    normalize_location(&input)
+}
+
+// Number of products created in each `bucket_ns`-wide bucket of
+// [`start_ns`, `end_ns`), as (bucket start, count) pairs including empty
+// buckets, e.g. for an intake sparkline. At most `MAX_HISTOGRAM_BUCKETS`
+// buckets.
+#[ic_cdk::query]
+fn creation_histogram(
+    bucket_ns: u64,
+    start_ns: u64,
+    end_ns: u64,
+    include_test: Option<bool>,
+) -> Result<Vec<(u64, u64)>, Error> {
+    ensure_migrated()?;
+    if bucket_ns == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Bucket size must be greater than zero".to_string(),
+        });
+    }
+    if start_ns > end_ns {
+        return Err(Error::InvalidInput {
+            msg: "Start must not be after end".to_string(),
+        });
+    }
+    let buckets = (end_ns - start_ns).div_ceil(bucket_ns);
+    if buckets > MAX_HISTOGRAM_BUCKETS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Range spans {} buckets, more than the limit of {}",
+                buckets, MAX_HISTOGRAM_BUCKETS
+            ),
+        });
+    }
+
+    let mut counts = vec![0u64; buckets as usize];
+    let created: Vec<(u64, u64)> = TIMESTAMP_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((start_ns, 0)..(end_ns, 0))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for (timestamp, id) in created {
+        if _get_product(&id).is_some_and(|product| is_included(&product, include_test)) {
+            counts[((timestamp - start_ns) / bucket_ns) as usize] += 1;
+        }
+    }
+    Ok(counts
+        .into_iter()
+        .enumerate()
+        .map(|(index, count)| (start_ns + index as u64 * bucket_ns, count))
+        .collect())
+}
+
+// Combined weight (grams) of all products currently in `status`.
+// Products without a recorded weight are ignored.
+#[ic_cdk::query]
+fn total_shipment_weight_by_status(status: ProductStatus, include_test: Option<bool>) -> u64 {
+    let status = status.canonical();
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, product)| product.status == status && is_included(product, include_test))
+            .filter_map(|(_, product)| product.weight_grams)
+            .fold(0u64, |total, weight| total.saturating_add(weight))
+    })
+}
+
+// Combined declared value (cents) of all products currently in `status`
+// valued in `currency`, e.g. for insurance reporting. Products without a
+// declared value, or valued in another currency, are ignored.
+#[ic_cdk::query]
+fn total_declared_value_by_status(
+    status: ProductStatus,
+    currency: String,
+    include_test: Option<bool>,
+) -> u64 {
+    let status = status.canonical();
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, product)| {
+                product.status == status
+                    && product.currency.as_deref() == Some(currency.as_str())
+                    && is_included(product, include_test)
+            })
+            .filter_map(|(_, product)| product.declared_value_cents)
+            .fold(0u64, |total, value| total.saturating_add(value))
+    })
+}
+
+// Products without a real owner (unset, or the anonymous principal), e.g.
+// after a bulk import, so an admin can assign them
+#[ic_cdk::query]
+fn unassigned_products(include_test: Option<bool>) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| !has_owner(product) && is_included(product, include_test))
+            .collect()
+    })
+}
+
+// Assign a product to a new owner (admin only)
+#[ic_cdk::update]
+fn assign_owner(id: u64, owner: Principal) -> Result<Product, Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    if owner == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "Cannot assign a product to the anonymous principal".to_string(),
+        });
+    }
+    match _get_product(&id) {
+        Some(mut product) => {
+            let previous = product.owner;
+            product.owner = Some(owner);
+            product.last_update = Some(time());
+            product.last_modified_by = Some(caller());
+            check_size(&product)?;
+            do_insert(&product);
+            let detail = format!("{} -> {}", describe_principal(previous), owner);
+            record_audit(id, "owner_assigned", detail);
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Cannot assign owner of product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Move every product owned by `from` to `to` (admin only), e.g. when
+// accounts are merged. Returns how many products moved.
+#[ic_cdk::update]
+fn transfer_all_ownership(from: Principal, to: Principal) -> Result<u64, Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    if to == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "Cannot assign products to the anonymous principal".to_string(),
+        });
+    }
+    if from == to {
+        return Ok(0);
+    }
+
+    let owned: Vec<Product> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.owner == Some(from))
+            .collect()
+    });
+    let moved: Vec<Product> = owned
+        .into_iter()
+        .map(|mut product| {
+            product.owner = Some(to);
+            product.last_update = Some(time());
+            product.last_modified_by = Some(caller());
+            product
+        })
+        .collect();
+    // Either every product moves or none does
+    for product in &moved {
+        check_size(product)?;
+    }
+    for product in &moved {
+        do_insert(product);
+        record_audit(product.id, "owner_assigned", format!("{} -> {}", from, to));
+    }
+    Ok(moved.len() as u64)
+}
+
+// All products as CSV (RFC 4180): a header row plus one row per product with
+// its scalar fields. History and other nested data are not included.
+#[ic_cdk::query]
+fn export_products_csv(include_test: Option<bool>) -> String {
+    let mut csv = format!("{}\r\n", CSV_HEADER);
+    PRODUCT_STORAGE.with(|storage| {
+        for (_, product) in storage.borrow().iter() {
+            if is_included(&product, include_test) {
+                csv.push_str(&csv_row(&product));
+            }
+        }
+    });
+    csv
+}
+
+// One page of the CSV export, so large datasets can be fetched in several
+// calls and concatenated. Request the header only with the first page.
+// `offset` counts within the exported products, so pass the same
+// `include_test` for every page.
+#[ic_cdk::query]
+fn export_products_csv_page(
+    offset: u64,
+    limit: u64,
+    include_header: bool,
+    include_test: Option<bool>,
+) -> String {
+    let mut csv = if include_header {
+        format!("{}\r\n", CSV_HEADER)
+    } else {
+        String::new()
+    };
+    PRODUCT_STORAGE.with(|storage| {
+        for (_, product) in storage
+            .borrow()
+            .iter()
+            .filter(|(_, product)| is_included(product, include_test))
+            .skip(offset as usize)
+            .take(page_limit(limit))
+        {
+            csv.push_str(&csv_row(&product));
+        }
+    });
+    csv
+}
+
+// Archive a product (owner or admin). Archived products stay readable but
+// no longer count towards the owner's quota.
+#[ic_cdk::update]
+fn archive_product(id: u64) -> Result<Product, Error> {
+    ensure_writable()?;
+    set_archived(id, true)
+}
+
+// Bring an archived product back (owner or admin)
+#[ic_cdk::update]
+fn unarchive_product(id: u64) -> Result<Product, Error> {
+    ensure_writable()?;
+    set_archived(id, false)
+}
+
+// Current canister settings
+#[ic_cdk::query]
+fn get_config() -> Config {
+    config()
+}
+
+// Number of non-archived products owned by `owner`
+#[ic_cdk::query]
+fn owner_product_count(owner: Principal) -> u64 {
+    owner_count(&owner)
+}
+
+// Every owner with their number of non-archived products, most first, e.g.
+// for a tenant overview (admin only). Unlike `owner_product_count`, which
+// reports what the quota counts, test products are only counted with
+// `include_test`.
+#[ic_cdk::query]
+fn list_owners_with_counts(include_test: Option<bool>) -> Result<Vec<(Principal, u64)>, Error> {
+    ensure_admin()?;
+    let mut test_counts: BTreeMap<Principal, u64> = BTreeMap::new();
+    if !include_test.unwrap_or(false) {
+        PRODUCT_STORAGE.with(|storage| {
+            for (_, product) in storage.borrow().iter() {
+                if product.is_test.unwrap_or(false)
+                    && has_owner(&product)
+                    && product.archived_at.is_none()
+                {
+                    *test_counts.entry(product.owner.unwrap()).or_default() += 1;
+                }
+            }
+        });
+    }
+    let mut owners: Vec<(Principal, u64)> = OWNER_COUNTS.with(|counts| {
+        counts
+            .borrow()
+            .iter()
+            .map(|(owner, count)| {
+                let owner = Principal::from_slice(owner.as_slice());
+                (
+                    owner,
+                    count.saturating_sub(test_counts.get(&owner).copied().unwrap_or(0)),
+                )
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect()
+    });
+    owners.sort_by_key(|&(owner, count)| (std::cmp::Reverse(count), owner));
+    Ok(owners)
+}
+
+// Limit how many non-archived products a single owner may hold (admin only).
+// `None` removes the limit.
+#[ic_cdk::update]
+fn set_max_products_per_owner(limit: Option<u64>) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    update_config(|config| config.max_products_per_owner = limit);
+    Ok(())
+}
+
+// Start a chunked export: snapshot the current product ids and return a
+// token to pass to `export_chunk`. Snapshots expire after an hour.
+#[ic_cdk::update]
+fn begin_export() -> Result<u64, Error> {
+    ensure_writable()?;
+    let now = time();
+    // Allocate before expiring so the newest token is never removed and
+    // token numbers are not reused
+    let token = EXPORT_TOKENS.with(|tokens| {
+        let mut tokens = tokens.borrow_mut();
+        let token = tokens.last_key_value().map_or(0, |(token, _)| token + 1);
+        tokens.insert(token, now);
+        token
+    });
+    expire_exports(now);
+    let ids: Vec<u64> =
+        PRODUCT_STORAGE.with(|storage| storage.borrow().iter().map(|(id, _)| id).collect());
+    EXPORT_SNAPSHOTS.with(|snapshots| {
+        let mut snapshots = snapshots.borrow_mut();
+        for (position, id) in ids.into_iter().enumerate() {
+            snapshots.insert((token, position as u64), id);
+        }
+    });
+    Ok(token)
+}
+
+// Fetch chunk `chunk_index` of the export started with `begin_export`.
+// Products deleted since the snapshot was taken are left out.
+#[ic_cdk::query]
+fn export_chunk(token: u64, chunk_index: u64) -> Result<ExportChunk, Error> {
+    match EXPORT_TOKENS.with(|tokens| tokens.borrow().get(&token)) {
+        Some(created_at) if time().saturating_sub(created_at) <= EXPORT_TTL_NS => {}
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("Export token {} is unknown or has expired", token),
+            })
+        }
+    }
+    let start = chunk_index.saturating_mul(EXPORT_CHUNK_SIZE);
+    let end = start.saturating_add(EXPORT_CHUNK_SIZE);
+    EXPORT_SNAPSHOTS.with(|snapshots| {
+        let snapshots = snapshots.borrow();
+        let products = snapshots
+            .range((token, start)..(token, end))
+            .filter_map(|(_, id)| _get_product(&id))
+            .collect();
+        let has_more = snapshots
+            .range((token, end)..=(token, u64::MAX))
+            .next()
+            .is_some();
+        Ok(ExportChunk { products, has_more })
+    })
+}
+
+// Status given to new products submitted with an empty one (admin only).
+// `None` restores the default behaviour of rejecting an empty status.
+#[ic_cdk::update]
+fn set_default_status(status: Option<ProductStatus>) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    if status.as_ref().is_some_and(ProductStatus::is_blank) {
+        return Err(Error::InvalidInput {
+            msg: "Default status must not be empty".to_string(),
+        });
+    }
+    update_config(|config| {
+        config.default_status = status.map(|status| status.canonical().to_string())
+    });
+    Ok(())
+}
+
+// Statuses products stay in once they reach them (admin only): status and
+// location changes are rejected until `reopen_product`. `None` restores the
+// default of just "Delivered"; an empty list disables the rule.
+#[ic_cdk::update]
+fn set_terminal_statuses(statuses: Option<Vec<ProductStatus>>) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    if let Some(statuses) = &statuses {
+        if statuses.iter().any(ProductStatus::is_blank) {
+            return Err(Error::InvalidInput {
+                msg: "Terminal statuses must not be empty".to_string(),
+            });
+        }
+    }
+    update_config(|config| {
+        config.terminal_statuses = statuses.map(|statuses| {
+            statuses
+                .into_iter()
+                .map(|status| status.canonical().to_string())
+                .collect()
+        })
+    });
+    Ok(())
+}
+
+// Statuses products stay in once they reach them
+#[ic_cdk::query]
+fn get_terminal_statuses() -> Vec<ProductStatus> {
+    terminal_statuses()
+}
+
+// Move a product out of its terminal status, back to the latest other status
+// in its history (admin only). `reason` is kept in the audit log.
+#[ic_cdk::update]
+fn reopen_product(id: u64, reason: String) -> Result<Product, Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let reason = reason.trim().to_string();
+    if reason.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "A reason is required to reopen a product".to_string(),
+        });
+    }
+    let mut product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Cannot reopen product with id={}. Product not found", id),
+    })?;
+    if !is_terminal(&product.status) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Product with id={} is not in a terminal status ({})",
+                id, product.status
+            ),
+        });
+    }
+    let status = get_history(id)
+        .into_iter()
+        .rev()
+        .map(|event| event.status)
+        .find(|status| !is_terminal(status))
+        .ok_or_else(|| Error::InvalidInput {
+            msg: format!("Product with id={} has no earlier status to reopen to", id),
+        })?;
+
+    let before = product.clone();
+    product.status = status;
+    product.last_update = Some(time());
+    product.last_modified_by = Some(caller());
+    stamp_changed_fields(&before, &mut product, time());
+    check_size(&product)?;
+    do_insert(&product);
+    record_status_event(&product, time());
+    record_audit(id, "reopened", reason);
+    Ok(product)
+}
+
+// Move a product to another lot, or out of any lot with `None` (owner or
+// admin), e.g. when it is repackaged
+#[ic_cdk::update]
+fn set_lot(id: u64, lot_id: Option<String>) -> Result<Product, Error> {
+    ensure_writable()?;
+    if let Some(lot_id) = &lot_id {
+        if lot_id.trim().is_empty() || lot_id.chars().count() > MAX_LOT_ID_LEN {
+            return Err(Error::InvalidInput {
+                msg: format!("Lot id must be 1 to {} characters long", MAX_LOT_ID_LEN),
+            });
+        }
+    }
+    match _get_product(&id) {
+        Some(mut product) => {
+            ensure_owner_or_admin(&product)?;
+            let detail = format!(
+                "{} -> {}",
+                product.lot_id.as_deref().unwrap_or("(none)"),
+                lot_id.as_deref().unwrap_or("(none)")
+            );
+            product.lot_id = lot_id;
+            product.last_update = Some(time());
+            product.last_modified_by = Some(caller());
+            check_size(&product)?;
+            do_insert(&product);
+            record_audit(id, "lot_changed", detail);
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Cannot set lot of product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Products currently in the given lot
+#[ic_cdk::query]
+fn get_products_by_lot(lot_id: String, include_test: Option<bool>) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product.lot_id.as_deref() == Some(lot_id.as_str())
+                    && is_included(product, include_test)
+            })
+            .collect()
+    })
+}
+
+// Set the metadata entry `key` of a product to `value`, replacing any
+// previous value (owner or admin)
+#[ic_cdk::update]
+fn set_metadata(id: u64, key: String, value: String) -> Result<Product, Error> {
+    ensure_writable()?;
+    let key = key.trim().to_string();
+    if key.is_empty() || key.chars().count() > MAX_METADATA_KEY_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Metadata key must be 1 to {} characters long",
+                MAX_METADATA_KEY_LEN
+            ),
+        });
+    }
+    match _get_product(&id) {
+        Some(mut product) => {
+            ensure_owner_or_admin(&product)?;
+            let metadata = product.metadata.get_or_insert_with(Vec::new);
+            match metadata.iter_mut().find(|(existing, _)| *existing == key) {
+                Some((_, existing)) => *existing = value,
+                None => metadata.push((key.clone(), value)),
+            }
+            let bytes: usize = metadata
+                .iter()
+                .map(|(key, value)| key.len() + value.len())
+                .sum();
+            if bytes > MAX_METADATA_BYTES {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Metadata would take {} bytes, more than the limit of {}",
+                        bytes, MAX_METADATA_BYTES
+                    ),
+                });
+            }
+            product.last_update = Some(time());
+            product.last_modified_by = Some(caller());
+            check_size(&product)?;
+            do_insert(&product);
+            record_audit(id, "metadata_set", key);
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Cannot set metadata of product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Remove the metadata entry `key` of a product (owner or admin)
+#[ic_cdk::update]
+fn remove_metadata(id: u64, key: String) -> Result<Product, Error> {
+    ensure_writable()?;
+    let key = key.trim().to_string();
+    match _get_product(&id) {
+        Some(mut product) => {
+            ensure_owner_or_admin(&product)?;
+            let metadata = product.metadata.take().unwrap_or_default();
+            let before = metadata.len();
+            let metadata: Vec<(String, String)> = metadata
+                .into_iter()
+                .filter(|(existing, _)| *existing != key)
+                .collect();
+            if metadata.len() == before {
+                return Err(Error::NotFound {
+                    msg: format!("Product with id={} has no metadata '{}'", id, key),
+                });
+            }
+            product.metadata = if metadata.is_empty() {
+                None
+            } else {
+                Some(metadata)
+            };
+            product.last_update = Some(time());
+            product.last_modified_by = Some(caller());
+            check_size(&product)?;
+            do_insert(&product);
+            record_audit(id, "metadata_removed", key);
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Cannot remove metadata of product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Metadata entries of a product, in the order they were first set
+#[ic_cdk::query]
+fn get_metadata(id: u64) -> Result<Vec<(String, String)>, Error> {
+    match _get_product(&id) {
+        Some(product) => Ok(product.metadata.unwrap_or_default()),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Products whose metadata entry `key` is exactly `value`
+#[ic_cdk::query]
+fn products_with_metadata(key: String, value: String, include_test: Option<bool>) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product
+                    .metadata
+                    .as_ref()
+                    .is_some_and(|metadata| metadata.contains(&(key.clone(), value.clone())))
+                    && is_included(product, include_test)
+            })
+            .collect()
+    })
+}
+
+// Audit events recorded for a product, oldest first. Events of deleted
+// products are kept.
+#[ic_cdk::query]
+fn get_audit_log(product_id: u64) -> Vec<AuditEvent> {
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| event.product_id == product_id)
+            .collect()
+    })
+}
+
+// Audit events recorded for a product, newest first, a page at a time
+#[ic_cdk::query]
+fn get_audit_log_for_product_paginated(
+    product_id: u64,
+    offset: u64,
+    limit: u64,
+) -> Vec<AuditEvent> {
+    get_audit_log(product_id)
+        .into_iter()
+        .rev()
+        .skip(offset as usize)
+        .take(page_limit(limit))
+        .collect()
+}
+
+// A product as a JSON object holding only the requested fields, for clients
+// that need just one or two of them. Unknown field names are ignored.
+#[ic_cdk::query]
+fn get_product_fields(id: u64, fields: Vec<String>) -> Result<String, Error> {
+    let product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Product with id={} not found", id),
+    })?;
+    let mut all = match serde_json::to_value(&product) {
+        Ok(serde_json::Value::Object(all)) => all,
+        _ => unreachable!("Products serialize to JSON objects"),
+    };
+    let selected: serde_json::Map<String, serde_json::Value> = fields
+        .iter()
+        .filter_map(|field| all.remove(field).map(|value| (field.clone(), value)))
+        .collect();
+    Ok(serde_json::Value::Object(selected).to_string())
+}
+
+// Hand a product to a new custodian (owner or admin)
+#[ic_cdk::update]
+fn set_custodian(id: u64, custodian: Principal) -> Result<Product, Error> {
+    ensure_writable()?;
+    change_custodian(id, Some(custodian))
+}
+
+// Record that nobody but the owner holds the product (owner or admin)
+#[ic_cdk::update]
+fn clear_custodian(id: u64) -> Result<Product, Error> {
+    ensure_writable()?;
+    change_custodian(id, None)
+}
+
+// Products currently held by `custodian`
+#[ic_cdk::query]
+fn products_in_custody_of(custodian: Principal, include_test: Option<bool>) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product.custodian == Some(custodian) && is_included(product, include_test)
+            })
+            .collect()
+    })
+}
+
+// Products that changed hands more than `threshold` times, counting custodian
+// changes and owner assignments in the audit log, e.g. for fraud review
+#[ic_cdk::query]
+fn products_with_custody_changes_over(threshold: u64, include_test: Option<bool>) -> Vec<Product> {
+    let mut transfers: BTreeMap<u64, u64> = BTreeMap::new();
+    AUDIT_LOG.with(|log| {
+        for (_, event) in log.borrow().iter() {
+            if CUSTODY_ACTIONS.contains(&event.action.as_str()) {
+                *transfers.entry(event.product_id).or_default() += 1;
+            }
+        }
+    });
+    transfers
+        .into_iter()
+        .filter(|(_, count)| *count > threshold)
+        .filter_map(|(id, _)| _get_product(&id))
+        .filter(|product| is_included(product, include_test))
+        .collect()
+}
+
+// Up to `limit` geotagged products closest to the given point, nearest first.
+// Products without coordinates are skipped.
+#[ic_cdk::query]
+fn nearest_products(lat: f64, lon: f64, limit: u64, include_test: Option<bool>) -> Vec<Product> {
+    let mut ranked: Vec<(f64, Product)> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, product)| is_included(product, include_test))
+            .filter_map(|(_, product)| match (product.latitude, product.longitude) {
+                (Some(p_lat), Some(p_lon)) => Some((haversine_m(lat, lon, p_lat, p_lon), product)),
+                _ => None,
+            })
+            .collect()
+    });
+    ranked.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    ranked
+        .into_iter()
+        .take(page_limit(limit))
+        .map(|(_, product)| product)
+        .collect()
+}
+
+// Geotagged products as a GeoJSON FeatureCollection of Point features, ready
+// for mapping libraries. Products without coordinates are left out.
+#[ic_cdk::query]
+fn export_geojson(include_test: Option<bool>) -> String {
+    let features: Vec<serde_json::Value> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, product)| is_included(product, include_test))
+            .filter_map(|(_, product)| {
+                let (lat, lon) = (product.latitude?, product.longitude?);
+                Some(serde_json::json!({
+                    "type": "Feature",
+                    "geometry": { "type": "Point", "coordinates": [lon, lat] },
+                    "properties": {
+                        "id": product.id,
+                        "name": product.name,
+                        "status": product.status.as_str(),
+                    },
+                }))
+            })
+            .collect()
+    });
+    serde_json::json!({ "type": "FeatureCollection", "features": features }).to_string()
+}
+
+// Largest page any paginated query returns
+#[ic_cdk::query]
+fn get_max_page_size() -> u64 {
+    max_page_size()
+}
+
+// Shortest query the text search accepts, in characters
+#[ic_cdk::query]
+fn get_min_search_length() -> u64 {
+    min_search_length()
+}
+
+// Change the shortest query the text search accepts (admin only). Must be at
+// least 1.
+#[ic_cdk::update]
+fn set_min_search_length(length: u64) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    if length == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Minimum search length must be at least 1".to_string(),
+        });
+    }
+    update_config(|config| config.min_search_length = Some(length));
+    Ok(())
+}
+
+// Change the largest page paginated queries return (admin only). Must be
+// between 1 and 1000.
+#[ic_cdk::update]
+fn set_max_page_size(size: u64) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    if !PAGE_SIZE_RANGE.contains(&size) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Page size must be between {} and {}",
+                PAGE_SIZE_RANGE.start(),
+                PAGE_SIZE_RANGE.end()
+            ),
+        });
+    }
+    update_config(|config| config.max_page_size = Some(size));
+    Ok(())
+}
+
+// How long a delivered product may go without updates before
+// `run_auto_archive` archives it (admin only). `None` turns auto-archiving
+// off.
+#[ic_cdk::update]
+fn set_auto_archive_after_ns(window_ns: Option<u64>) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    update_config(|config| config.auto_archive_after_ns = window_ns);
+    Ok(())
+}
+
+// Archive delivered products not updated within the configured window before
+// `now_ns` (admin only), e.g. from an off-chain scheduler. Products that are
+// not delivered yet are never archived. Returns how many were archived.
+#[ic_cdk::update]
+fn run_auto_archive(now_ns: u64) -> Result<u64, Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    Ok(auto_archive(now_ns, caller()))
+}
+
+// Run `run_auto_archive` every `secs` seconds from the canister's global
+// timer instead of an external scheduler (admin only). Every run scans all
+// products, so its cycle cost grows with the catalogue; hours rather than
+// seconds is a sensible cadence. The archive window still comes from
+// `set_auto_archive_after_ns`.
+#[ic_cdk::update]
+fn set_auto_archive_interval_secs(secs: u64) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    if secs == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Interval must be at least one second".to_string(),
+        });
+    }
+    update_config(|config| config.auto_archive_interval_secs = Some(secs));
+    schedule_auto_archive();
+    Ok(())
+}
+
+// Stop the periodic auto-archive (admin only). `run_auto_archive` can still
+// be called by hand.
+#[ic_cdk::update]
+fn disable_auto_archive() -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    update_config(|config| config.auto_archive_interval_secs = None);
+    schedule_auto_archive();
+    Ok(())
+}
+
+// The global timer is cleared by upgrades, so it is armed again from the
+// stored config
+#[ic_cdk::init]
+fn init() {
+    set_stored_schema_version(SCHEMA_VERSION);
+    schedule_auto_archive();
+}
+
+// Records left by an older version must be migrated before anything is
+// written; until then every update but `migrate_products` is rejected
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    if ensure_migrated().is_err() {
+        print(format!(
+            "Stored schema {} is behind {}; call migrate_products",
+            stored_schema_version(),
+            SCHEMA_VERSION
+        ));
+    }
+    schedule_auto_archive();
+}
+
+// ic-cdk 0.11 has no timer API and ic-cdk-timers is not a dependency, so the
+// auto-archive job drives the system global timer directly. Adding
+// ic-cdk-timers later means dropping this export, which it defines itself.
+#[export_name = "canister_global_timer"]
+extern "C" fn canister_global_timer() {
+    // Re-armed first so that the next run is scheduled before the scan
+    schedule_auto_archive();
+    if ensure_writable().is_ok() {
+        auto_archive(time(), ic_cdk::id());
+    }
+}
+
+// Canister whose `record_product(id, name)` is notified of every new product
+// (admin only); `None` stops the notifications
+#[ic_cdk::update]
+fn set_registry_canister(registry: Option<Principal>) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    update_config(|config| config.registry_canister = registry);
+    Ok(())
+}
+
+// Canister whose `get_enrichment(id) -> (text)` supplies data for
+// `enrich_product` (admin only); `None` disables enrichment
+#[ic_cdk::update]
+fn set_enrichment_canister(canister: Option<Principal>) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    update_config(|config| config.enrichment_canister = canister);
+    Ok(())
+}
+
+// Fetch supplementary data (e.g., a supplier rating) for a product from the
+// enrichment canister and store it in `enrichment` (owner or admin). The
+// product is read again after the call, so changes made meanwhile are kept,
+// and the checks are repeated, as in `verify_certification`.
+#[ic_cdk::update]
+async fn enrich_product(id: u64) -> Result<Product, Error> {
+    ensure_writable()?;
+    let canister = config()
+        .enrichment_canister
+        .ok_or_else(|| Error::InvalidInput {
+            msg: "No enrichment canister is configured".to_string(),
+        })?;
+    let product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Cannot enrich product with id={}. Product not found", id),
+    })?;
+    ensure_owner_or_admin(&product)?;
+    let actor = caller();
+
+    let (enrichment,): (String,) = ic_cdk::call(canister, "get_enrichment", (id,))
+        .await
+        .map_err(|(code, msg)| Error::RemoteCallFailed {
+            msg: format!(
+                "get_enrichment on {} failed ({:?}): {}",
+                canister, code, msg
+            ),
+        })?;
+
+    let mut product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Product with id={} was deleted during enrichment", id),
+    })?;
+    ensure_writable()?;
+    ensure_owner_or_admin(&product)?;
+    product.enrichment = Some(enrichment);
+    product.last_update = Some(time());
+    product.last_modified_by = Some(actor);
+    check_size(&product)?;
+    do_insert(&product);
+    record_audit_by(actor, id, "enriched", String::new());
+    Ok(product)
+}
+
+// Canister whose `is_valid(cert_id) -> (bool)` vouches for certifications
+// issued by `authority` (admin only); `None` forgets the authority
+#[ic_cdk::update]
+fn set_certification_authority(
+    authority: String,
+    canister: Option<Principal>,
+) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let key = authority_key(&authority)?;
+    CERT_AUTHORITIES.with(|authorities| {
+        let mut authorities = authorities.borrow_mut();
+        match canister {
+            Some(canister) => authorities.insert(key, principal_key(&canister)),
+            None => authorities.remove(&key),
+        }
+    });
+    Ok(())
+}
+
+// Configured certification authorities and their canisters, by name
+#[ic_cdk::query]
+fn list_certification_authorities() -> Vec<(String, Principal)> {
+    CERT_AUTHORITIES.with(|authorities| {
+        authorities
+            .borrow()
+            .iter()
+            .map(|(name, canister)| {
+                (
+                    String::from_utf8_lossy(name.as_slice()).into_owned(),
+                    Principal::from_slice(canister.as_slice()),
+                )
+            })
+            .collect()
+    })
+}
+
+// Ask the canister of a product's certification authority whether its
+// certification is valid, and store the answer in `cert_verified` (owner or
+// admin). The answer is dropped if the certification changed meanwhile, and
+// the checks are repeated after the call, since the canister may have been
+// frozen or the product reassigned while it was in flight.
+#[ic_cdk::update]
+async fn verify_certification(id: u64) -> Result<bool, Error> {
+    ensure_writable()?;
+    let product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!(
+            "Cannot verify certification of product with id={}. Product not found",
+            id
+        ),
+    })?;
+    ensure_owner_or_admin(&product)?;
+    let (certification, authority) = match (&product.certification, &product.cert_authority) {
+        (Some(certification), Some(authority)) => (certification.clone(), authority.clone()),
+        _ => {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Product with id={} has no certification with an authority",
+                    id
+                ),
+            })
+        }
+    };
+    let key = authority_key(&authority)?;
+    let canister = CERT_AUTHORITIES
+        .with(|authorities| authorities.borrow().get(&key))
+        .map(|canister| Principal::from_slice(canister.as_slice()))
+        .ok_or_else(|| Error::InvalidInput {
+            msg: format!("No canister is configured for authority '{}'", authority),
+        })?;
+    let actor = caller();
+
+    let (valid,): (bool,) = ic_cdk::call(canister, "is_valid", (certification.clone(),))
+        .await
+        .map_err(|(code, msg)| Error::RemoteCallFailed {
+            msg: format!("is_valid on {} failed ({:?}): {}", canister, code, msg),
+        })?;
+
+    let mut product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Product with id={} was deleted during verification", id),
+    })?;
+    ensure_writable()?;
+    ensure_owner_or_admin(&product)?;
+    if product.certification.as_ref() != Some(&certification)
+        || product.cert_authority.as_ref() != Some(&authority)
+    {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Certification of product with id={} changed during verification",
+                id
+            ),
+        });
+    }
+    product.cert_verified = Some(valid);
+    product.cert_verified_at = Some(time());
+    product.last_update = Some(time());
+    product.last_modified_by = Some(actor);
+    check_size(&product)?;
+    do_insert(&product);
+    let verdict = if valid { "valid" } else { "invalid" };
+    record_audit_by(actor, id, "certification_verified", verdict.to_string());
+    Ok(valid)
+}
+
+// Certified products whose certification expires in
+// (`now_ns`, `now_ns + window_ns`], soonest first, e.g. for renewal reminders
+#[ic_cdk::query]
+fn certifications_expiring_within(
+    window_ns: u64,
+    now_ns: u64,
+    include_test: Option<bool>,
+) -> Vec<Product> {
+    let until = now_ns.saturating_add(window_ns);
+    let mut products: Vec<Product> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product.certification.is_some()
+                    && product
+                        .cert_expires_at
+                        .is_some_and(|expires_at| expires_at > now_ns && expires_at <= until)
+                    && is_included(product, include_test)
+            })
+            .collect()
+    });
+    products.sort_by_key(|product| (product.cert_expires_at, product.id));
+    products
+}
+
+// Number of registry notifications that could not be sent
+#[ic_cdk::query]
+fn registry_notify_failures() -> u64 {
+    REGISTRY_NOTIFY_FAILURES.with(|failures| *failures.borrow().get())
+}
+
+// Products whose JSON `iot_data` holds `equals` at the dotted `path`, e.g.
+// "sensor.battery". Numbers and booleans are compared by their JSON text.
+// Products with no IoT data, unparseable data or no value at `path` are
+// skipped.
+#[ic_cdk::query]
+fn products_where_iot(path: String, equals: String, include_test: Option<bool>) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| is_included(product, include_test))
+            .filter(|product| {
+                let data = match product.iot_data.as_deref().map(serde_json::from_str) {
+                    Some(Ok(data)) => data,
+                    _ => return false,
+                };
+                let text = match json_at_path(&data, &path) {
+                    Some(serde_json::Value::String(value)) => value.clone(),
+                    Some(value) => value.to_string(),
+                    None => return false,
+                };
+                text == equals
+            })
+            .collect()
+    })
+}
+
+// Products matching `filter`, a page at a time in id order
+#[ic_cdk::query]
+fn query_products(filter: ProductFilter, offset: u64, limit: u64) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| matches_filter(product, &filter))
+            .skip(offset as usize)
+            .take(page_limit(limit))
+            .collect()
+    })
+}
+
+// Number of products `query_products` would find for `filter`, without
+// returning them
+#[ic_cdk::query]
+fn count_matching(filter: ProductFilter) -> u64 {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, product)| matches_filter(product, &filter))
+            .count() as u64
+    })
+}
+
+// Anchor an off-chain document (bill of lading, certificate, ...) to a product
+// by its SHA-256 hash (owner or admin)
+#[ic_cdk::update]
+fn attach_document(id: u64, name: String, sha256_hex: String) -> Result<DocumentRef, Error> {
+    ensure_writable()?;
+    if name.trim().is_empty() || name.chars().count() > MAX_DOCUMENT_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Document name must be 1 to {} characters long",
+                MAX_DOCUMENT_NAME_LEN
+            ),
+        });
+    }
+    validate_sha256_hex(&sha256_hex)?;
+    let product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!(
+            "Cannot attach document to product with id={}. Product not found",
+            id
+        ),
+    })?;
+    ensure_owner_or_admin(&product)?;
+
+    let document = DocumentRef {
+        name,
+        sha256_hex: sha256_hex.to_ascii_lowercase(),
+        added_at: time(),
+        added_by: caller(),
+    };
+    DOCUMENTS.with(|documents| {
+        let mut documents = documents.borrow_mut();
+        let next = documents
+            .range((id, 0)..=(id, u64::MAX))
+            .last()
+            .map_or(0, |((_, position), _)| position + 1);
+        documents.insert((id, next), document.clone());
+    });
+    record_audit(
+        id,
+        "document_attached",
+        format!("{} {}", document.name, document.sha256_hex),
+    );
+    Ok(document)
+}
+
+// Documents attached to a product, oldest first
+#[ic_cdk::query]
+fn get_documents(id: u64) -> Result<Vec<DocumentRef>, Error> {
+    match _get_product(&id) {
+        Some(_) => Ok(DOCUMENTS.with(|documents| {
+            documents
+                .borrow()
+                .range((id, 0)..=(id, u64::MAX))
+                .map(|(_, document)| document)
+                .collect()
+        })),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Anchor an off-chain photo of a product by its SHA-256 hash, with an
+// optional caption (owner or admin). At most `MAX_PHOTOS_PER_PRODUCT` photos
+// per product.
+#[ic_cdk::update]
+fn add_photo(id: u64, sha256_hex: String, caption: Option<String>) -> Result<PhotoRef, Error> {
+    ensure_writable()?;
+    validate_sha256_hex(&sha256_hex)?;
+    if caption
+        .as_ref()
+        .is_some_and(|caption| caption.chars().count() > MAX_PHOTO_CAPTION_LEN)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Caption must be at most {} characters long",
+                MAX_PHOTO_CAPTION_LEN
+            ),
+        });
+    }
+    let product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!(
+            "Cannot add photo to product with id={}. Product not found",
+            id
+        ),
+    })?;
+    ensure_owner_or_admin(&product)?;
+
+    let photo = PhotoRef {
+        sha256_hex: sha256_hex.to_ascii_lowercase(),
+        caption,
+        added_at: time(),
+    };
+    PHOTOS.with(|photos| {
+        let mut photos = photos.borrow_mut();
+        let next = photos
+            .range((id, 0)..=(id, u64::MAX))
+            .last()
+            .map_or(0, |((_, position), _)| position + 1);
+        if next >= MAX_PHOTOS_PER_PRODUCT {
+            return Err(Error::QuotaExceeded {
+                limit: MAX_PHOTOS_PER_PRODUCT,
+            });
+        }
+        photos.insert((id, next), photo.clone());
+        Ok(())
+    })?;
+    record_audit(id, "photo_added", photo.sha256_hex.clone());
+    Ok(photo)
+}
+
+// Photos anchored to a product, oldest first
+#[ic_cdk::query]
+fn get_photos(id: u64) -> Result<Vec<PhotoRef>, Error> {
+    match _get_product(&id) {
+        Some(_) => Ok(PHOTOS.with(|photos| {
+            photos
+                .borrow()
+                .range((id, 0)..=(id, u64::MAX))
+                .map(|(_, photo)| photo)
+                .collect()
+        })),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Leave a note on a product (owner or admin)
+#[ic_cdk::update]
+fn add_note(id: u64, text: String) -> Result<Note, Error> {
+    ensure_writable()?;
+    if text.trim().is_empty() || text.chars().count() > MAX_NOTE_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("Note must be 1 to {} characters long", MAX_NOTE_LEN),
+        });
+    }
+    let product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!(
+            "Cannot add note to product with id={}. Product not found",
+            id
+        ),
+    })?;
+    ensure_owner_or_admin(&product)?;
+
+    let note = Note {
+        text,
+        author: caller(),
+        added_at: time(),
+    };
+    NOTES.with(|notes| {
+        let mut notes = notes.borrow_mut();
+        let next = notes
+            .range((id, 0)..=(id, u64::MAX))
+            .last()
+            .map_or(0, |((_, position), _)| position + 1);
+        notes.insert((id, next), note.clone());
+    });
+    record_audit(id, "note_added", String::new());
+    Ok(note)
+}
+
+// Notes left on a product, oldest first
+#[ic_cdk::query]
+fn get_notes(id: u64) -> Result<Vec<Note>, Error> {
+    match _get_product(&id) {
+        Some(_) => Ok(NOTES.with(|notes| {
+            notes
+                .borrow()
+                .range((id, 0)..=(id, u64::MAX))
+                .map(|(_, note)| note)
+                .collect()
+        })),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Status changes, notes and audit events of a product in one list, oldest
+// first. Entries with the same timestamp keep that order.
+#[ic_cdk::query]
+fn get_timeline(id: u64) -> Result<Vec<TimelineEntry>, Error> {
+    let notes = get_notes(id)?;
+    let mut timeline: Vec<TimelineEntry> = get_history(id)
+        .into_iter()
+        .map(|event| TimelineEntry {
+            kind: "status".to_string(),
+            timestamp: event.timestamp,
+            detail: format!("{} at {}", event.status, event.location),
+        })
+        .collect();
+    timeline.extend(notes.into_iter().map(|note| TimelineEntry {
+        kind: "note".to_string(),
+        timestamp: note.added_at,
+        detail: note.text,
+    }));
+    timeline.extend(get_audit_log(id).into_iter().map(|event| TimelineEntry {
+        kind: "audit".to_string(),
+        timestamp: event.timestamp,
+        detail: if event.detail.is_empty() {
+            event.action
+        } else {
+            format!("{}: {}", event.action, event.detail)
+        },
+    }));
+    timeline.sort_by_key(|entry| entry.timestamp);
+    Ok(timeline)
+}
+
+// Everything stored about one product as a single JSON object with
+// "product", "history", "notes", "documents" and "photos" keys, e.g. for a
+// customer data export. History and notes are oldest first.
+#[ic_cdk::query]
+fn export_product_bundle_json(id: u64) -> Result<String, Error> {
+    let product = get_product(id)?;
+    let bundle = serde_json::json!({
+        "product": product,
+        "history": get_history(id),
+        "notes": get_notes(id)?,
+        "documents": get_documents(id)?,
+        "photos": get_photos(id)?,
+    });
+    Ok(bundle.to_string())
+}
+
+// Retrieve a product by its customer-facing tracking code
+#[ic_cdk::query]
+fn get_product_by_tracking_code(code: String) -> Result<Product, Error> {
+    find_by_tracking_code(&code).ok_or_else(|| Error::NotFound {
+        msg: format!("Product with tracking code '{}' not found", code),
+    })
+}
+
+// Retrieve a product by id or tracking code: `key` is tried as an id first,
+// then as a tracking code
+#[ic_cdk::query]
+fn lookup_product(key: String) -> Result<Product, Error> {
+    let product = match key.parse::<u64>() {
+        Ok(id) => _get_product(&id),
+        Err(_) => find_by_tracking_code(&key),
+    };
+    product.ok_or_else(|| Error::NotFound {
+        msg: format!("No product with id or tracking code '{}'", key),
+    })
+}
+
+// Place a product inside another one (owner or admin), or take it out of its
+// container with `None`. A product can't end up as its own ancestor.
+#[ic_cdk::update]
+fn set_parent(child_id: u64, parent_id: Option<u64>) -> Result<Product, Error> {
+    ensure_writable()?;
+    let mut child = _get_product(&child_id).ok_or_else(|| Error::NotFound {
+        msg: format!("Product with id={} not found", child_id),
+    })?;
+    ensure_owner_or_admin(&child)?;
+    if let Some(parent_id) = parent_id {
+        if _get_product(&parent_id).is_none() {
+            return Err(Error::NotFound {
+                msg: format!("Parent product with id={} not found", parent_id),
+            });
+        }
+        let ancestors = ancestor_ids(parent_id);
+        if parent_id == child_id || ancestors.contains(&child_id) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Product with id={} cannot be placed inside its own descendant",
+                    child_id
+                ),
+            });
+        }
+        // Levels above the parent, the parent, and the child's own subtree
+        if ancestors.len() + 1 + subtree_depth(child_id) > MAX_HIERARCHY_DEPTH {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Products can be nested at most {} levels deep",
+                    MAX_HIERARCHY_DEPTH
+                ),
+            });
+        }
+    }
+    let detail = format!(
+        "{} -> {}",
+        child
+            .parent_id
+            .map_or("(none)".to_string(), |id| id.to_string()),
+        parent_id.map_or("(none)".to_string(), |id| id.to_string())
+    );
+    child.parent_id = parent_id;
+    child.last_update = Some(time());
+    child.last_modified_by = Some(caller());
+    check_size(&child)?;
+    do_insert(&child);
+    record_audit(child_id, "parent_changed", detail);
+    Ok(child)
+}
+
+// Products directly contained in the given product
+#[ic_cdk::query]
+fn get_children(id: u64) -> Vec<Product> {
+    children_of(id)
+}
+
+// Allow products to move from status `from` to `to` (admin only). Once any
+// transition is configured, only configured transitions are allowed.
+#[ic_cdk::update]
+fn allow_transition(from: ProductStatus, to: ProductStatus) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let key = (status_key(&from)?, status_key(&to)?);
+    TRANSITIONS.with(|transitions| transitions.borrow_mut().insert(key, ()));
+    Ok(())
+}
+
+// Remove a configured transition (admin only). Removing the last one allows
+// every transition again.
+#[ic_cdk::update]
+fn disallow_transition(from: ProductStatus, to: ProductStatus) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let key = (status_key(&from)?, status_key(&to)?);
+    TRANSITIONS.with(|transitions| transitions.borrow_mut().remove(&key));
+    Ok(())
+}
+
+// Configured (from, to) transitions; empty when every transition is allowed
+#[ic_cdk::query]
+fn list_transitions() -> Vec<(ProductStatus, ProductStatus)> {
+    TRANSITIONS.with(|transitions| {
+        transitions
+            .borrow()
+            .iter()
+            .map(|((from, to), _)| (status_from_key(&from), status_from_key(&to)))
+            .collect()
+    })
+}
+
+// Add an origin to the allowlist (admin only). Once any origin is listed,
+// new and patched products must name a listed origin.
+#[ic_cdk::update]
+fn add_allowed_origin(origin: String) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let key = origin_key(&origin)?;
+    ALLOWED_ORIGINS.with(|origins| origins.borrow_mut().insert(key, ()));
+    Ok(())
+}
+
+// Remove an origin from the allowlist (admin only). Removing the last one
+// allows every origin again. Existing products keep their origin.
+#[ic_cdk::update]
+fn remove_allowed_origin(origin: String) -> Result<(), Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let key = origin_key(&origin)?;
+    ALLOWED_ORIGINS.with(|origins| origins.borrow_mut().remove(&key));
+    Ok(())
+}
+
+// Origins on the allowlist, sorted; empty when every origin is allowed
+#[ic_cdk::query]
+fn list_allowed_origins() -> Vec<String> {
+    ALLOWED_ORIGINS.with(|origins| {
+        origins
+            .borrow()
+            .iter()
+            .map(|(key, _)| String::from_utf8_lossy(key.as_slice()).into_owned())
+            .collect()
+    })
+}
+
+// Set the status of a product and, transitively, of everything inside it
+// (owner or admin). Contained products whose transition is not allowed, that
+// the caller may not modify, or that would outgrow the size limit, are
+// skipped; the named product itself is never skipped. Returns how many products
+// changed status.
+#[ic_cdk::update]
+fn update_status_cascade(id: u64, new_status: ProductStatus) -> Result<u64, Error> {
+    ensure_writable()?;
+    let new_status = new_status.canonical();
+    if new_status.is_blank() {
+        return Err(Error::InvalidInput {
+            msg: "Field 'status' must not be empty".to_string(),
+        });
+    }
+    let root = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Cannot update product with id={}. Product not found", id),
+    })?;
+    ensure_owner_or_admin(&root)?;
+    check_transition(&root.status, &new_status)?;
+
+    let mut updated = 0;
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![root];
+    while let Some(mut product) = pending.pop() {
+        if !visited.insert(product.id) {
+            continue;
+        }
+        pending.extend(children_of(product.id));
+        let allowed = product.id == id
+            || (ensure_owner_or_admin(&product).is_ok()
+                && check_transition(&product.status, &new_status).is_ok());
+        if !allowed || product.status == new_status {
+            continue;
+        }
+        let before = product.clone();
+        product.status = new_status.clone();
+        product.last_update = Some(time());
+        product.last_modified_by = Some(caller());
+        stamp_changed_fields(&before, &mut product, time());
+        if product.id == id {
+            check_size(&product)?;
+        } else if check_size(&product).is_err() {
+            continue;
+        }
+        do_insert(&product);
+        record_status_event(&product, time());
+        record_audit(
+            product.id,
+            "status_cascaded",
+            format!("from product {}", id),
+        );
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+// Move every product in status `from` to status `to` (admin only), e.g. for
+// end-of-day processing. Fails before any write if the transition is not
+// allowed. Returns how many products were advanced.
+#[ic_cdk::update]
+fn advance_all_in_status(from: ProductStatus, to: ProductStatus) -> Result<u64, Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let from = from.canonical();
+    let to = to.canonical();
+    if from.is_blank() || to.is_blank() {
+        return Err(Error::InvalidInput {
+            msg: "Statuses must not be empty".to_string(),
+        });
+    }
+    check_transition(&from, &to)?;
+    if from == to {
+        return Ok(0);
+    }
+
+    let now = time();
+    let mut advanced = Vec::new();
+    for before in PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.status == from)
+            .collect::<Vec<_>>()
+    }) {
+        let mut product = before.clone();
+        product.status = to.clone();
+        product.last_update = Some(now);
+        product.last_modified_by = Some(caller());
+        stamp_changed_fields(&before, &mut product, now);
+        check_size(&product)?;
+        advanced.push(product);
+    }
+    for product in &advanced {
+        do_insert(product);
+        record_status_event(product, now);
+        record_audit(product.id, "status_advanced", format!("{} -> {}", from, to));
+    }
+    Ok(advanced.len() as u64)
+}
+
+// Containers of a product from its immediate parent up to the root, e.g. for
+// a "Unit > Box > Pallet > Container" breadcrumb
+#[ic_cdk::query]
+fn get_ancestors(id: u64) -> Result<Vec<Product>, Error> {
+    let mut current = _get_product(&id)
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        })?
+        .parent_id;
+    let mut ancestors: Vec<Product> = Vec::new();
+    while let Some(parent_id) = current {
+        // `set_parent` prevents both cases, so reaching them means corrupt data
+        if parent_id == id || ancestors.iter().any(|ancestor| ancestor.id == parent_id) {
+            return Err(Error::Internal {
+                msg: format!("Product hierarchy above id={} contains a loop", id),
+            });
+        }
+        if ancestors.len() >= MAX_HIERARCHY_DEPTH {
+            return Err(Error::Internal {
+                msg: format!(
+                    "Product hierarchy above id={} is deeper than {} levels",
+                    id, MAX_HIERARCHY_DEPTH
+                ),
+            });
+        }
+        match _get_product(&parent_id) {
+            Some(parent) => {
+                current = parent.parent_id;
+                ancestors.push(parent);
+            }
+            None => break,
+        }
+    }
+    Ok(ancestors)
+}
+
+// Rewrite every stored product in the current schema (admin only). Records
+// written by older versions decode with `None` for fields added since; this
+// fills in what can be derived (the owner of products created before
+// ownership was tracked is their creator) and stores them again. Free-text
+// statuses, in products and their history, are stored as `ProductStatus`
+// from then on. Returns the number of products rewritten.
+#[ic_cdk::update]
+fn migrate_products() -> Result<u64, Error> {
+    ensure_not_read_only()?;
+    ensure_admin()?;
+    let ids: Vec<u64> =
+        PRODUCT_STORAGE.with(|storage| storage.borrow().iter().map(|(id, _)| id).collect());
+    let mut migrated = 0;
+    for id in ids {
+        if let Some(stored) = _get_product(&id) {
+            // Indexing leaves the record as it is, so every product is
+            // indexed even if it cannot be rewritten
+            update_timestamp_index(Some(&stored), Some(&stored));
+            let mut product = stored;
+            if product.owner.is_none() {
+                product.owner = product.created_by;
+            }
+            // Left in its old layout, which still decodes
+            if check_size(&product).is_err() {
+                print(format!("Cannot migrate product {}: too large", id));
+                continue;
+            }
+            do_insert(&product);
+            migrated += 1;
+        }
+    }
+
+    let events: Vec<((u64, u64), StatusEvent)> =
+        STATUS_HISTORY.with(|history| history.borrow().iter().collect());
+    STATUS_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        for (key, event) in events {
+            history.insert(key, event);
+        }
+    });
+    set_stored_schema_version(SCHEMA_VERSION);
+    Ok(migrated)
+}
+
+// Freeze or unfreeze the canister for maintenance (admin only). While
+// read-only, every update except this one is rejected; queries still work.
+#[ic_cdk::update]
+fn set_read_only(read_only: bool) -> Result<(), Error> {
+    ensure_admin()?;
+    update_config(|config| config.read_only = Some(read_only));
+    Ok(())
+}
+
+// Whether the canister currently rejects updates for maintenance
+#[ic_cdk::query]
+fn is_read_only() -> bool {
+    config().read_only.unwrap_or(false)
+}
+
+// Schema version this code reads and writes
+#[ic_cdk::query]
+fn schema_version() -> u32 {
+    SCHEMA_VERSION
+}
+
+// Schema version of the stored records. Lower than `schema_version()` until
+// an admin runs `migrate_products`.
+#[ic_cdk::query]
+fn stored_schema_version() -> u32 {
+    STORED_SCHEMA_VERSION.with(|version| *version.borrow().get())
+}
+
+// Every `MemoryId` in use and what it holds
+#[ic_cdk::query]
+fn memory_layout() -> Vec<(u8, String)> {
+    memory_ids::LAYOUT
+        .iter()
+        .map(|(id, purpose)| (*id, purpose.to_string()))
+        .collect()
+}
+
+// Number of entries in each stable storage, to see what is growing. Cells
+// always hold a single entry.
+#[ic_cdk::query]
+fn memory_report() -> Vec<(String, u64)> {
+    vec![
+        ("id_counter".to_string(), 1),
+        (
+            "products".to_string(),
+            PRODUCT_STORAGE.with(|m| m.borrow().len()),
+        ),
+        (
+            "status_history".to_string(),
+            STATUS_HISTORY.with(|m| m.borrow().len()),
+        ),
+        ("config".to_string(), 1),
+        (
+            "owner_counts".to_string(),
+            OWNER_COUNTS.with(|m| m.borrow().len()),
+        ),
+        (
+            "export_tokens".to_string(),
+            EXPORT_TOKENS.with(|m| m.borrow().len()),
+        ),
+        (
+            "export_snapshots".to_string(),
+            EXPORT_SNAPSHOTS.with(|m| m.borrow().len()),
+        ),
+        ("audit".to_string(), AUDIT_LOG.with(|m| m.borrow().len())),
+        (
+            "documents".to_string(),
+            DOCUMENTS.with(|m| m.borrow().len()),
+        ),
+        (
+            "transitions".to_string(),
+            TRANSITIONS.with(|m| m.borrow().len()),
+        ),
+        ("change_seq".to_string(), 1),
+        (
+            "change_ring".to_string(),
+            CHANGE_RING.with(|m| m.borrow().len()),
+        ),
+        ("registry_notify_failures".to_string(), 1),
+        ("schema_version".to_string(), 1),
+        (
+            "reservations".to_string(),
+            RESERVATIONS.with(|m| m.borrow().len()),
+        ),
+        ("purged".to_string(), PURGED.with(|m| m.borrow().len())),
+        ("photos".to_string(), PHOTOS.with(|m| m.borrow().len())),
+        ("notes".to_string(), NOTES.with(|m| m.borrow().len())),
+        (
+            "undo_snapshots".to_string(),
+            UNDO_SNAPSHOTS.with(|m| m.borrow().len()),
+        ),
+        (
+            "allowed_origins".to_string(),
+            ALLOWED_ORIGINS.with(|m| m.borrow().len()),
+        ),
+        (
+            "cert_authorities".to_string(),
+            CERT_AUTHORITIES.with(|m| m.borrow().len()),
+        ),
+        (
+            "timestamp_index".to_string(),
+            TIMESTAMP_INDEX.with(|m| m.borrow().len()),
+        ),
+    ]
+}
+
+// Audit events with a sequence number greater than `seq`, oldest first, for
+// incremental sync: pass the last `seq` seen (or 0 to start from scratch)
+#[ic_cdk::query]
+fn audit_events_since(seq: u64, limit: u64) -> Vec<AuditEvent> {
+    if seq == u64::MAX {
+        return Vec::new();
+    }
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .range(seq + 1..)
+            .map(|(_, event)| event)
+            .take(page_limit(limit))
+            .collect()
+    })
+}
+
+// Sequence number of the latest mutation. Poll this and call
+// `changed_product_ids_since` only when it moved.
+#[ic_cdk::query]
+fn current_change_seq() -> u64 {
+    CHANGE_SEQ.with(|seq| *seq.borrow().get())
+}
+
+// Ids of products changed after `seq`, ordered by their latest change. Only
+// the last `CHANGE_RING_SIZE` changes are retained: a client whose `seq` is
+// older than `current_change_seq() - CHANGE_RING_SIZE` has missed changes
+// and must reload all products instead.
+#[ic_cdk::query]
+fn changed_product_ids_since(seq: u64) -> Vec<u64> {
+    let mut changes: Vec<(u64, u64)> = CHANGE_RING.with(|ring| {
+        ring.borrow()
+            .iter()
+            .map(|(_, change)| change)
+            .filter(|(change_seq, _)| *change_seq > seq)
+            .collect()
+    });
+    changes.sort_unstable();
+
+    let mut seen = BTreeSet::new();
+    let mut ids: Vec<u64> = changes
+        .into_iter()
+        .rev()
+        .filter(|(_, id)| seen.insert(*id))
+        .map(|(_, id)| id)
+        .collect();
+    ids.reverse();
+    ids
+}
+
+// A page of the audit log as a JSON array, oldest first, for off-chain
+// archival. Feed the pages to `import_audit_json` to move them elsewhere.
+#[ic_cdk::query]
+fn export_audit_json_page(offset: u64, limit: u64) -> String {
+    let events: Vec<AuditEvent> = AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .skip(offset as usize)
+            .take(page_limit(limit))
+            .map(|(_, event)| event)
+            .collect()
+    });
+    serde_json::to_string(&events).expect("Cannot serialize audit events")
+}
+
+// Append audit events exported with `export_audit_json_page` (admin only).
+// Events keep their sequence number; those with `seq` 0 are numbered after
+// the current last event, in order. Nothing is imported if any sequence
+// number is already taken (`Error::Conflict`) or an event is malformed.
+// Events imported below the last sequence number are not seen by clients
+// already past them in `audit_events_since`. Returns how many were imported.
+#[ic_cdk::update]
+fn import_audit_json(json: String) -> Result<u64, Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let mut events: Vec<AuditEvent> =
+        serde_json::from_str(&json).map_err(|e| Error::InvalidInput {
+            msg: format!("Invalid audit JSON: {}", e),
+        })?;
+
+    let mut taken = BTreeSet::new();
+    for event in events.iter().filter(|event| event.seq != 0) {
+        let exists = AUDIT_LOG.with(|log| log.borrow().contains_key(&event.seq));
+        if exists || !taken.insert(event.seq) {
+            return Err(Error::Conflict {
+                msg: format!("Audit sequence number {} is already in use", event.seq),
+            });
+        }
+    }
+    let first_free = AUDIT_LOG
+        .with(|log| log.borrow().last_key_value().map(|(seq, _)| seq))
+        .into_iter()
+        .chain(taken.last().copied())
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let unnumbered = events.iter_mut().filter(|event| event.seq == 0);
+    for (seq, event) in (first_free..).zip(unnumbered) {
+        event.seq = seq;
+    }
+    for event in &events {
+        let size = event.to_bytes().len();
+        if size > AuditEvent::MAX_SIZE as usize {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Audit event {} is {} bytes encoded, exceeding the limit of {} bytes",
+                    event.seq,
+                    size,
+                    AuditEvent::MAX_SIZE
+                ),
+            });
+        }
+    }
+
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        for event in &events {
+            log.insert(event.seq, event.clone());
+        }
+    });
+    Ok(events.len() as u64)
+}
+
+// Store products under the ids they carry, e.g. to mirror another canister
+// (admin only). Every product is checked first, so either all are stored or
+// none. Fails with `Conflict` if an id is already taken, reserved or was
+// deleted, and `u64::MAX` is never accepted. Parents must be stored or part
+// of the batch, within the usual nesting rules. The id counter moves past
+// the highest imported id. Each product starts its history with its current
+// status.
+#[ic_cdk::update]
+fn import_products_with_ids(mut products: Vec<Product>) -> Result<u64, Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let mut ids = BTreeSet::new();
+    let mut codes = BTreeSet::new();
+    for product in &mut products {
+        product.status = product.status.clone().canonical();
+        let id = product.id;
+        if id == u64::MAX {
+            return Err(Error::InvalidInput {
+                msg: format!("Product id={} cannot be imported", id),
+            });
+        }
+        let taken = PRODUCT_STORAGE.with(|storage| storage.borrow().contains_key(&id))
+            || RESERVATIONS.with(|reservations| reservations.borrow().contains_key(&id));
+        if taken || !ids.insert(id) {
+            return Err(Error::Conflict {
+                msg: format!("Product id={} is already in use", id),
+            });
+        }
+        if PURGED.with(|purged| purged.borrow().contains_key(&id)) {
+            return Err(Error::Conflict {
+                msg: format!("Product id={} was deleted and cannot be reused", id),
+            });
+        }
+        validate_product(product)?;
+        check_deadlines(
+            product.estimated_delivery_ns,
+            product.sla_deadline_ns,
+            product.timestamp,
+        )?;
+        if let Some(code) = &product.tracking_code {
+            if !codes.insert(code.clone()) {
+                return Err(Error::InvalidInput {
+                    msg: format!("Tracking code '{}' is used more than once", code),
+                });
+            }
+        }
+        check_tracking_code_free(&product.tracking_code)?;
+    }
+    check_imported_hierarchy(&products)?;
+
+    for product in &products {
+        do_insert(product);
+        record_status_event(product, product.timestamp);
+        record_audit(product.id, "imported", String::new());
+    }
+    if let Some(&last) = ids.last() {
+        ID_COUNTER.with(|counter| {
+            let next = (*counter.borrow().get()).max(last.saturating_add(1));
+            counter
+                .borrow_mut()
+                .set(next)
+                .expect("Cannot advance ID counter");
+        });
+    }
+    Ok(products.len() as u64)
+}
+
+// Fingerprint of a product's current state (hex SHA-256 of its encoding).
+// It changes whenever any field changes, so clients can use it like an ETag.
+#[ic_cdk::query]
+fn get_product_fingerprint(id: u64) -> Result<String, Error> {
+    match _get_product(&id) {
+        Some(product) => Ok(fingerprint(&product)),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Conditional get: `Ok(None)` when the product still has `known_fingerprint`,
+// otherwise the current product
+#[ic_cdk::query]
+fn get_product_if_changed(id: u64, known_fingerprint: String) -> Result<Option<Product>, Error> {
+    match _get_product(&id) {
+        Some(product) if fingerprint(&product) == known_fingerprint => Ok(None),
+        Some(product) => Ok(Some(product)),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Current fingerprint of each requested product, or `None` for ids that no
+// longer exist, so a client cache can tell which records to refetch
+#[ic_cdk::query]
+fn fingerprints_for(ids: Vec<u64>) -> Vec<(u64, Option<String>)> {
+    ids.into_iter()
+        .map(|id| (id, _get_product(&id).map(|product| fingerprint(&product))))
+        .collect()
+}
+
+// Dashboard widgets in one call, from a single pass over the products.
+// Archived products are counted too. There is no recall workflow yet, so a
+// product counts as recalled while its status is `Custom("Recalled")`.
+#[ic_cdk::query]
+fn dashboard_snapshot(include_test: Option<bool>) -> DashboardSnapshot {
+    let now = time();
+    let mut total = 0;
+    let mut statuses: BTreeMap<ProductStatus, u64> = BTreeMap::new();
+    let mut latest: Vec<Product> = Vec::with_capacity(DASHBOARD_LATEST + 1);
+    let mut overdue_count = 0;
+    let mut recalled_count = 0;
+    PRODUCT_STORAGE.with(|storage| {
+        for (_, product) in storage.borrow().iter() {
+            if !is_included(&product, include_test) {
+                continue;
+            }
+            total += 1;
+            if is_overdue(&product, now) {
+                overdue_count += 1;
+            }
+            if is_recalled(&product) {
+                recalled_count += 1;
+            }
+            *statuses.entry(status_bucket(&product.status)).or_default() += 1;
+
+            let position = latest.partition_point(|other| other.timestamp >= product.timestamp);
+            if position < DASHBOARD_LATEST {
+                latest.insert(position, product);
+                latest.truncate(DASHBOARD_LATEST);
+            }
+        }
+    });
+    DashboardSnapshot {
+        total,
+        status_breakdown: statuses.into_iter().collect(),
+        latest_products: latest,
+        overdue_count,
+        recalled_count,
+    }
+}
+
+// Non-archived products not yet delivered whose ETA is before `now_ns`
+#[ic_cdk::query]
+fn overdue_products(now_ns: u64, include_test: Option<bool>) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| is_overdue(product, now_ns) && is_included(product, include_test))
+            .collect()
+    })
+}
+
+// Products whose name is within `max_distance` edits (Levenshtein,
+// case-insensitive) of `query`, closest first and then in id order.
+// `max_distance` is capped at `MAX_FUZZY_DISTANCE`. Queries shorter than
+// `get_min_search_length()` once trimmed are rejected, as in `search_all_text`.
+#[ic_cdk::query]
+fn search_products_fuzzy(
+    query: String,
+    max_distance: u32,
+    limit: u64,
+    include_test: Option<bool>,
+) -> Result<Vec<Product>, Error> {
+    let query = query.trim().to_lowercase();
+    check_search_query(&query)?;
+    let query: Vec<char> = query.chars().collect();
+    let max_distance = max_distance.min(MAX_FUZZY_DISTANCE) as usize;
+    let mut ranked: Vec<(usize, Product)> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, product)| is_included(product, include_test))
+            .filter_map(|(_, product)| {
+                let name: Vec<char> = product.name.to_lowercase().chars().collect();
+                let distance = edit_distance(&query, &name);
+                (distance <= max_distance).then_some((distance, product))
+            })
+            .collect()
+    });
+    ranked.sort_by_key(|(distance, product)| (*distance, product.id));
+    Ok(ranked
+        .into_iter()
+        .take(page_limit(limit))
+        .map(|(_, product)| product)
+        .collect())
+}
+
+// Non-archived products not yet delivered whose SLA deadline is before
+// `now_ns`, with how far past it they are
+#[ic_cdk::query]
+fn sla_breached(now_ns: u64, include_test: Option<bool>) -> Vec<SlaBreach> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter_map(|(_, product)| match product.sla_deadline_ns {
+                Some(deadline)
+                    if deadline < now_ns
+                        && product.archived_at.is_none()
+                        && is_included(&product, include_test)
+                        && product.status != ProductStatus::Delivered =>
+                {
+                    Some(SlaBreach {
+                        product,
+                        overdue_ns: now_ns - deadline,
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+// The non-archived product not yet delivered that was created first, or
+// `None` if there is none
+#[ic_cdk::query]
+fn oldest_undelivered(include_test: Option<bool>) -> Result<Option<Product>, Error> {
+    ensure_migrated()?;
+    Ok(TIMESTAMP_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .filter_map(|((_, id), _)| _get_product(&id))
+            .find(|product| {
+                product.archived_at.is_none()
+                    && product.status != ProductStatus::Delivered
+                    && is_included(product, include_test)
+            })
+    }))
+}
+
+// Non-archived products not yet delivered that have an SLA deadline, the
+// least time left first (already breached ones come first), up to `limit`
+// results, e.g. for a dispatcher's watch list
+#[ic_cdk::query]
+fn products_nearing_sla(now_ns: u64, limit: u64, include_test: Option<bool>) -> Vec<Product> {
+    let mut products: Vec<Product> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                is_included(product, include_test)
+                    && product.sla_deadline_ns.is_some()
+                    && product.archived_at.is_none()
+                    && product.status != ProductStatus::Delivered
+            })
+            .collect()
+    });
+    // Negative once the deadline has passed
+    let time_left = |product: &Product| {
+        product
+            .sla_deadline_ns
+            .map_or(0, |deadline| deadline as i128 - now_ns as i128)
+    };
+    products.sort_by_key(|product| (time_left(product), product.id));
+    products.truncate(page_limit(limit));
+    products
+}
+
+// Products whose name, origin or current location contains `query`
+// (case-insensitive), up to `limit` results in id order. Queries shorter than
+// `get_min_search_length()` once trimmed are rejected, since they would
+// match nearly everything.
+#[ic_cdk::query]
+fn search_all_text(
+    query: String,
+    limit: u64,
+    include_test: Option<bool>,
+) -> Result<Vec<Product>, Error> {
+    let query = query.trim().to_lowercase();
+    check_search_query(&query)?;
+    Ok(PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| is_included(product, include_test))
+            .filter(|product| {
+                [&product.name, &product.origin, &product.current_location]
+                    .iter()
+                    .any(|text| text.to_lowercase().contains(&query))
+            })
+            .take(page_limit(limit))
+            .collect()
+    }))
+}
+
+// Add `tag` to every product matching `filter` (admin only). Products that
+// already carry the tag, or that have no room left for it, are skipped.
+// Returns how many products were tagged.
+#[ic_cdk::update]
+fn tag_products_by_filter(filter: ProductFilter, tag: String) -> Result<u64, Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let tag = tag.trim().to_string();
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("Tag must be 1 to {} characters long", MAX_TAG_LEN),
+        });
+    }
+
+    let matching: Vec<Product> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| matches_filter(product, &filter))
+            .filter(|product| {
+                !product
+                    .tags
+                    .as_ref()
+                    .is_some_and(|tags| tags.contains(&tag))
+            })
+            .collect()
+    });
+    let mut tagged = 0;
+    for mut product in matching {
+        product.tags.get_or_insert_with(Vec::new).push(tag.clone());
+        product.last_update = Some(time());
+        product.last_modified_by = Some(caller());
+        if check_size(&product).is_err() {
+            continue;
+        }
+        do_insert(&product);
+        record_audit(product.id, "tagged", tag.clone());
+        tagged += 1;
+    }
+    Ok(tagged)
+}
+
+// Non-archived products grouped by status, for a kanban board. Each column
+// holds at most `limit_per_status` products, the newest first.
+#[ic_cdk::query]
+fn kanban_view(limit_per_status: u64, include_test: Option<bool>) -> Vec<KanbanColumn> {
+    let limit = page_limit(limit_per_status);
+    let mut columns: BTreeMap<ProductStatus, KanbanColumn> = BTreeMap::new();
+    PRODUCT_STORAGE.with(|storage| {
+        for (_, product) in storage.borrow().iter() {
+            if product.archived_at.is_some() || !is_included(&product, include_test) {
+                continue;
+            }
+            let status = status_bucket(&product.status);
+            let column = columns.entry(status.clone()).or_insert(KanbanColumn {
+                status,
+                total: 0,
+                products: Vec::new(),
+            });
+            column.total += 1;
+            let position = column
+                .products
+                .partition_point(|other| other.timestamp >= product.timestamp);
+            if position < limit {
+                column.products.insert(position, product);
+                column.products.truncate(limit);
+            }
+        }
+    });
+    columns.into_values().collect()
+}
+
+// Every tag with the number of non-archived products carrying it, most used
+// first and then in tag order, e.g. for a tag cloud
+#[ic_cdk::query]
+fn tag_counts(include_test: Option<bool>) -> Vec<(String, u64)> {
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    PRODUCT_STORAGE.with(|storage| {
+        for (_, product) in storage.borrow().iter() {
+            if product.archived_at.is_some() || !is_included(&product, include_test) {
+                continue;
+            }
+            for tag in product.tags.unwrap_or_default() {
+                *counts.entry(tag).or_default() += 1;
+            }
+        }
+    });
+    let mut counts: Vec<(String, u64)> = counts.into_iter().collect();
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    counts
+}
+
+// Products matching `filter` plus per-status and per-origin counts over all
+// matches. Counts cover every match; `items` holds at most one page of them.
+#[ic_cdk::query]
+fn query_products_faceted(filter: ProductFilter) -> FacetedResult {
+    let limit = page_limit(u64::MAX);
+    let mut items = Vec::new();
+    let mut statuses: BTreeMap<ProductStatus, u64> = BTreeMap::new();
+    let mut origins: BTreeMap<String, u64> = BTreeMap::new();
+    PRODUCT_STORAGE.with(|storage| {
+        for (_, product) in storage.borrow().iter() {
+            if !matches_filter(&product, &filter) {
+                continue;
+            }
+            *statuses.entry(status_bucket(&product.status)).or_default() += 1;
+            *origins.entry(product.origin.clone()).or_default() += 1;
+            if items.len() < limit {
+                items.push(product);
+            }
+        }
+    });
+    FacetedResult {
+        items,
+        status_counts: statuses.into_iter().collect(),
+        origin_counts: origins.into_iter().collect(),
+    }
+}
+
+// Change only the fields set in `patch` (owner or admin). The patch is
+// all-or-nothing: if any field is rejected, nothing is written.
+#[ic_cdk::update]
+fn patch_product(id: u64, patch: ProductPatch) -> Result<Product, Error> {
+    ensure_writable()?;
+    do_patch(id, patch)
+}
+
+// Apply each patch on its own, as `patch_product` would, e.g. for a bulk-edit
+// grid. A rejected patch only fails its own item.
+#[ic_cdk::update]
+fn patch_products_batch(patches: Vec<(u64, ProductPatch)>) -> Result<Vec<PatchResult>, Error> {
+    ensure_writable()?;
+    Ok(patches
+        .into_iter()
+        .map(|(id, patch)| PatchResult {
+            id,
+            result: do_patch(id, patch),
+        })
+        .collect())
+}
+
+// When each mutable field of a product last changed, for fields that have
+// changed since this was tracked
+#[ic_cdk::query]
+fn get_field_history(id: u64) -> Result<Vec<(String, u64)>, Error> {
+    match _get_product(&id) {
+        Some(product) => Ok(product.field_updated_at.unwrap_or_default()),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Mutable fields whose values differ between two products, e.g. to compare a
+// unit against a template. Unset optional fields read as "".
+#[ic_cdk::query]
+fn diff_products(a_id: u64, b_id: u64) -> Result<Vec<FieldDiff>, Error> {
+    let find = |id: u64| {
+        _get_product(&id).ok_or_else(|| Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        })
+    };
+    let a = find(a_id)?;
+    let b = find(b_id)?;
+    Ok(field_values(&a)
+        .into_iter()
+        .zip(field_values(&b))
+        .filter(|((_, a), (_, b))| a != b)
+        .map(|((field, a), (_, b))| FieldDiff {
+            field: field.to_string(),
+            a,
+            b,
+        })
+        .collect())
+}
+
+// Restore a product to how it was before its latest `update_product` or
+// `patch_product` (owner or admin). Only one level is kept, so a second undo
+// fails until the product is updated again.
+#[ic_cdk::update]
+fn undo_last_update(id: u64) -> Result<Product, Error> {
+    ensure_writable()?;
+    let current = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!(
+            "Cannot undo update of product with id={}. Product not found",
+            id
+        ),
+    })?;
+    ensure_owner_or_admin(&current)?;
+    let mut product = UNDO_SNAPSHOTS
+        .with(|snapshots| snapshots.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("No update of product with id={} to undo", id),
+        })?;
+    check_leaving_terminal(&current, &product.status)?;
+    check_location_change(&current, &product)?;
+
+    // Changes made since by other endpoints are not part of the snapshot's
+    // scope, so they are kept
+    product.owner = current.owner;
+    product.archived_at = current.archived_at;
+    product.lot_id = current.lot_id.clone();
+    product.custodian = current.custodian;
+    product.parent_id = current.parent_id;
+    product.tags = current.tags.clone();
+    product.metadata = current.metadata.clone();
+    product.enrichment = current.enrichment.clone();
+    product.last_update = Some(time());
+    product.last_modified_by = Some(caller());
+    // Restored fields count as changed now, not when the snapshot was taken
+    product.field_updated_at = current.field_updated_at.clone();
+    stamp_changed_fields(&current, &mut product, time());
+    check_size(&product)?;
+    UNDO_SNAPSHOTS.with(|snapshots| snapshots.borrow_mut().remove(&id));
+    do_insert(&product);
+    if product.status != current.status {
+        record_status_event(&product, time());
+    }
+    record_audit(id, "update_undone", String::new());
+    Ok(product)
+}
+
+// Reset a product's status and location to those of its history entry
+// `entry_index` (0 is the oldest), e.g. after a mis-scan (owner or admin).
+// Later entries are removed and a new entry records the rollback itself.
+// Configured transitions are not checked, since this corrects the history
+// rather than moving the product on, but a product in a terminal status
+// needs `reopen_product` first.
+#[ic_cdk::update]
+fn rollback_to_history_entry(id: u64, entry_index: u64) -> Result<Product, Error> {
+    ensure_writable()?;
+    let mut product = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Cannot roll back product with id={}. Product not found", id),
+    })?;
+    ensure_owner_or_admin(&product)?;
+    let entries: Vec<((u64, u64), StatusEvent)> =
+        STATUS_HISTORY.with(|history| history.borrow().range((id, 0)..=(id, u64::MAX)).collect());
+    let index = usize::try_from(entry_index).unwrap_or(usize::MAX);
+    let target = match entries.get(index) {
+        Some((_, event)) => event.clone(),
+        None => {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Product with id={} has {} history entries, so there is no entry {}",
+                    id,
+                    entries.len(),
+                    entry_index
+                ),
+            })
+        }
+    };
+
+    let before = product.clone();
+    check_leaving_terminal(&before, &target.status)?;
+    product.status = target.status;
+    product.current_location = target.location;
+    check_location_change(&before, &product)?;
+    product.last_update = Some(time());
+    product.last_modified_by = Some(caller());
+    stamp_changed_fields(&before, &mut product, time());
+    check_size(&product)?;
+    let later = &entries[index + 1..];
+    STATUS_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        for (key, _) in later {
+            history.remove(key);
+        }
+    });
+    do_insert(&product);
+    record_status_event(&product, time());
+    let detail = format!(
+        "to entry {}, {} later entries removed",
+        entry_index,
+        later.len()
+    );
+    record_audit(id, "rolled_back", detail);
+    Ok(product)
+}
+
+// Mark a product as still current without changing any field (owner or
+// admin): only `last_update` is set, e.g. by a periodic heartbeat
+#[ic_cdk::update]
+fn touch_product(id: u64) -> Result<Product, Error> {
+    ensure_writable()?;
+    match _get_product(&id) {
+        Some(mut product) => {
+            ensure_owner_or_admin(&product)?;
+            product.last_update = Some(time());
+            product.last_modified_by = Some(caller());
+            check_size(&product)?;
+            do_insert(&product);
+            record_audit(id, "touched", String::new());
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Cannot touch product with id={}. Product not found", id),
+        }),
+    }
+}
+
+// Update product details (e.g., location, status, certification, IoT data)
+// (owner or admin)
+#[ic_cdk::update]
+fn update_product(id: u64, mut payload: ProductPayload) -> Result<Product, Error> {
+    ensure_writable()?;
+    payload.status = payload.status.canonical();
+    validate_product_payload(&payload)?;
     match PRODUCT_STORAGE.with(|storage| storage.borrow().get(&id)) {
         Some(mut product) => {
+            ensure_owner_or_admin(&product)?;
+            let before = product.clone();
+            check_transition(&product.status, &payload.status)?;
+            check_deadlines(
+                payload.estimated_delivery_ns,
+                payload.sla_deadline_ns,
+                product.timestamp,
+            )?;
+            let status_changed = product.status != payload.status;
             product.current_location = payload.current_location;
             product.status = payload.status;
             product.certification = payload.certification;
             product.iot_data = payload.iot_data;
+            product.weight_grams = payload.weight_grams;
+            product.dimensions_mm = payload.dimensions_mm;
+            product.latitude = payload.latitude;
+            product.longitude = payload.longitude;
+            product.estimated_delivery_ns = payload.estimated_delivery_ns;
+            product.priority = payload.priority;
+            product.sla_deadline_ns = payload.sla_deadline_ns;
+            product.declared_value_cents = payload.declared_value_cents;
+            product.currency = payload.currency;
+            product.cert_authority = payload.cert_authority;
+            product.cert_expires_at = payload.cert_expires_at;
+            product.last_update = Some(time());
+            product.last_modified_by = Some(caller());
+            check_location_change(&before, &product)?;
+            stamp_changed_fields(&before, &mut product, time());
+            reset_cert_verification(&before, &mut product);
+            check_size(&product)?;
+            save_undo_snapshot(&before);
+            do_insert(&product);
+            if status_changed {
+                record_status_event(&product, time());
+            }
+            record_audit(id, "updated", String::new());
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Cannot update product with id={}. Product not found", id),
+        }),
+    }
+}
+
+// Remove a product's certification (e.g., when it has been decertified).
+// Unlike sending `None` through `update_product`, this leaves every other
+// field untouched. Clearing an uncertified product is not an error; it just
+// stamps `last_update` (owner or admin).
+#[ic_cdk::update]
+fn clear_certification(id: u64) -> Result<Product, Error> {
+    ensure_writable()?;
+    match _get_product(&id) {
+        Some(mut product) => {
+            ensure_owner_or_admin(&product)?;
+            product.certification = None;
+            product.cert_verified = None;
+            product.cert_verified_at = None;
+            product.cert_expires_at = None;
+            product.last_update = Some(time());
+            product.last_modified_by = Some(caller());
+            check_size(&product)?;
+            do_insert(&product);
+            record_audit(id, "certification_cleared", String::new());
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Cannot clear certification of product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// When the product with `id` was deleted, or `None` if it still exists or
+// never did
+#[ic_cdk::query]
+fn was_purged(id: u64) -> Option<u64> {
+    if PRODUCT_STORAGE.with(|storage| storage.borrow().contains_key(&id)) {
+        return None;
+    }
+    PURGED.with(|purged| purged.borrow().get(&id))
+}
+
+// Delete a product entry by ID (owner or admin)
+#[ic_cdk::update]
+fn delete_product(id: u64) -> Result<Product, Error> {
+    ensure_writable()?;
+    if let Some(product) = _get_product(&id) {
+        ensure_owner_or_admin(&product)?;
+    }
+    match remove_product(id) {
+        Some(product) => {
+            record_audit(id, "deleted", String::new());
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Cannot delete product with id={}. Product not found.", id),
+        }),
+    }
+}
+
+// Delete every product created as test data (admin only). Returns how many
+// were deleted.
+#[ic_cdk::update]
+fn purge_test_products() -> Result<u64, Error> {
+    ensure_writable()?;
+    ensure_admin()?;
+    let ids = test_product_ids();
+    for &id in &ids {
+        remove_product(id);
+        record_audit(id, "deleted", "test data purge".to_string());
+    }
+    Ok(ids.len() as u64)
+}
+
+// Helper method to delete a product with everything stored alongside it.
+// Returns the deleted product, if there was one.
+fn remove_product(id: u64) -> Option<Product> {
+    let product = PRODUCT_STORAGE.with(|storage| storage.borrow_mut().remove(&id))?;
+    update_owner_counts(Some(&product), None);
+    update_timestamp_index(Some(&product), None);
+    clear_product_records(id);
+    for mut child in children_of(id) {
+        child.parent_id = None;
+        let unstamped = child.clone();
+        child.last_update = Some(time());
+        child.last_modified_by = Some(caller());
+        // Clearing the parent never grows a record, but the stamps can
+        if check_size(&child).is_err() {
+            child = unstamped;
+        }
+        do_insert(&child);
+        record_audit(child.id, "parent_changed", format!("{} -> (none)", id));
+    }
+    PURGED.with(|purged| purged.borrow_mut().insert(id, time()));
+    Some(product)
+}
+
+// Helper method shared by `archive_product` and `unarchive_product`
+fn set_archived(id: u64, archived: bool) -> Result<Product, Error> {
+    match _get_product(&id) {
+        Some(mut product) => {
+            ensure_owner_or_admin(&product)?;
+            if product.archived_at.is_some() != archived {
+                product.archived_at = if archived { Some(time()) } else { None };
+                product.last_update = Some(time());
+                product.last_modified_by = Some(caller());
+                check_size(&product)?;
+                do_insert(&product);
+                record_audit(
+                    id,
+                    if archived { "archived" } else { "unarchived" },
+                    String::new(),
+                );
+            }
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} not found", id),
+        }),
+    }
+}
+
+// Helper method shared by `set_custodian` and `clear_custodian`
+fn change_custodian(id: u64, custodian: Option<Principal>) -> Result<Product, Error> {
+    match _get_product(&id) {
+        Some(mut product) => {
+            ensure_owner_or_admin(&product)?;
+            let detail = format!(
+                "{} -> {}",
+                describe_principal(product.custodian),
+                describe_principal(custodian)
+            );
+            product.custodian = custodian;
             product.last_update = Some(time());
+            product.last_modified_by = Some(caller());
+            check_size(&product)?;
             do_insert(&product);
+            record_audit(id, "custodian_changed", detail);
             Ok(product)
         }
-        None => Err(Error::NotFound {
-            msg: format!("Cannot update product with id={}. Product not found", id),
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Cannot change custodian of product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Helper method to list the products directly inside `id`
+fn children_of(id: u64) -> Vec<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.parent_id == Some(id))
+            .collect()
+    })
+}
+
+// Helper method to count the levels of `id` and everything below it
+fn subtree_depth(id: u64) -> usize {
+    let mut depth = 0;
+    let mut level = vec![id];
+    let mut visited = BTreeSet::new();
+    while !level.is_empty() && depth <= MAX_HIERARCHY_DEPTH {
+        depth += 1;
+        level = level
+            .into_iter()
+            .filter(|id| visited.insert(*id))
+            .flat_map(|id| children_of(id).into_iter().map(|child| child.id))
+            .collect();
+    }
+    depth
+}
+
+// Helper method to collect the ids above `id` in the hierarchy, nearest
+// first. Stops if it runs into a loop.
+fn ancestor_ids(id: u64) -> Vec<u64> {
+    let mut ancestors = Vec::new();
+    let mut current = _get_product(&id).and_then(|product| product.parent_id);
+    while let Some(parent_id) = current {
+        if parent_id == id || ancestors.contains(&parent_id) {
+            break;
+        }
+        ancestors.push(parent_id);
+        current = _get_product(&parent_id).and_then(|product| product.parent_id);
+    }
+    ancestors
+}
+
+// Helper method to hold imported products to the rules of `set_parent`:
+// every parent exists in storage or in the batch, and no chain loops or
+// nests deeper than `MAX_HIERARCHY_DEPTH`
+fn check_imported_hierarchy(products: &[Product]) -> Result<(), Error> {
+    let batch: BTreeMap<u64, Option<u64>> = products
+        .iter()
+        .map(|product| (product.id, product.parent_id))
+        .collect();
+    let parent_of = |id: u64| match batch.get(&id) {
+        Some(parent_id) => *parent_id,
+        None => _get_product(&id).and_then(|product| product.parent_id),
+    };
+    for product in products {
+        let mut chain = vec![product.id];
+        let mut current = product.parent_id;
+        while let Some(parent_id) = current {
+            if !batch.contains_key(&parent_id) && _get_product(&parent_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Parent product with id={} not found", parent_id),
+                });
+            }
+            if chain.contains(&parent_id) {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Product id={} would be placed inside its own descendant",
+                        parent_id
+                    ),
+                });
+            }
+            chain.push(parent_id);
+            if chain.len() > MAX_HIERARCHY_DEPTH {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Products can be nested at most {} levels deep",
+                        MAX_HIERARCHY_DEPTH
+                    ),
+                });
+            }
+            current = parent_of(parent_id);
+        }
+    }
+    Ok(())
+}
+
+// Helper method to archive delivered products whose archive window ended
+// before `now_ns`, on behalf of `actor`. Products that would outgrow
+// `Product::MAX_SIZE` are skipped and left for an admin to trim.
+fn auto_archive(now_ns: u64, actor: Principal) -> u64 {
+    let window = match config().auto_archive_after_ns {
+        Some(window) => window,
+        None => return 0,
+    };
+    let expired: Vec<Product> = PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product.archived_at.is_none()
+                    && product.status == ProductStatus::Delivered
+                    && product
+                        .last_update
+                        .unwrap_or(product.timestamp)
+                        .saturating_add(window)
+                        < now_ns
+            })
+            .collect()
+    });
+
+    let mut archived = 0;
+    for mut product in expired {
+        product.archived_at = Some(time());
+        product.last_update = Some(time());
+        product.last_modified_by = Some(actor);
+        if check_size(&product).is_err() {
+            print(format!(
+                "Cannot auto-archive product {}: too large",
+                product.id
+            ));
+            continue;
+        }
+        do_insert(&product);
+        record_audit_by(actor, product.id, "archived", "auto-archive".to_string());
+        archived += 1;
+    }
+    archived
+}
+
+// Helper method to arm the global timer for the next auto-archive run, or
+// disarm it when no interval is configured
+fn schedule_auto_archive() {
+    let deadline = config().auto_archive_interval_secs.map_or(0, |secs| {
+        time().saturating_add(secs.saturating_mul(1_000_000_000))
+    });
+    // A deadline of 0 cancels the timer
+    unsafe {
+        ic0::global_timer_set(deadline as i64);
+    }
+}
+
+// Helper method to tell the configured registry about a new product. This is
+// a one-way call: failures are counted and logged but never undo the insert.
+fn notify_registry(product: &Product) {
+    let registry = match config().registry_canister {
+        Some(registry) => registry,
+        None => return,
+    };
+    let args = (product.id, product.name.clone());
+    if let Err(code) = ic_cdk::api::call::notify(registry, "record_product", args) {
+        print(format!(
+            "Cannot notify registry {} of product {}: {:?}",
+            registry, product.id, code
+        ));
+        REGISTRY_NOTIFY_FAILURES.with(|failures| {
+            let mut failures = failures.borrow_mut();
+            let count = *failures.get() + 1;
+            failures
+                .set(count)
+                .expect("Cannot update the registry failure counter");
+        });
+    }
+}
+
+// Helper method to reject updates in read-only mode or before migration
+fn ensure_writable() -> Result<(), Error> {
+    ensure_not_read_only()?;
+    ensure_migrated()
+}
+
+// Helper method to reject updates while an admin froze the canister
+fn ensure_not_read_only() -> Result<(), Error> {
+    if config().read_only.unwrap_or(false) {
+        return Err(Error::Internal {
+            msg: "canister is read-only".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to reject writes while the stored records are at an older
+// schema than this code
+fn ensure_migrated() -> Result<(), Error> {
+    if stored_schema_version() < SCHEMA_VERSION {
+        return Err(Error::Internal {
+            msg: "migration required".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to record the schema version of the stored records
+fn set_stored_schema_version(version: u32) {
+    STORED_SCHEMA_VERSION.with(|cell| {
+        cell.borrow_mut()
+            .set(version)
+            .expect("Cannot update the schema version");
+    });
+}
+
+// Helper method to fill in the default status and run every check a new
+// product must pass
+fn check_new_product(payload: &mut ProductPayload) -> Result<(), Error> {
+    check_new_payload(payload)?;
+    check_owner_quota(&caller())
+}
+
+// Helper method to run the checks of `check_new_product` that depend on the
+// payload alone, i.e. all but the owner quota
+fn check_new_payload(payload: &mut ProductPayload) -> Result<(), Error> {
+    if let Some(history) = &mut payload.initial_history {
+        check_initial_history(history, time())?;
+        if let Some(last) = history.last() {
+            payload.status = last.status.clone();
+        }
+    }
+    if payload.status.is_blank() {
+        if let Some(status) = config().default_status {
+            payload.status = ProductStatus::parse(&status);
+        }
+    }
+    validate_product_payload(payload)?;
+    check_origin_allowed(&payload.origin)?;
+    check_deadlines(
+        payload.estimated_delivery_ns,
+        payload.sla_deadline_ns,
+        time(),
+    )?;
+    check_tracking_code_free(&payload.tracking_code)
+}
+
+// Helper method to take the next product id
+fn allocate_product_id() -> Result<u64, Error> {
+    let id = ID_COUNTER.with(|counter| *counter.borrow().get());
+    // The counter only moves forward, so a taken id means it was corrupted.
+    // Bail out before touching the counter or storage.
+    if PRODUCT_STORAGE.with(|storage| storage.borrow().contains_key(&id)) {
+        return Err(Error::Internal {
+            msg: format!("Next product id={} is already in use", id),
+        });
+    }
+    ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(id + 1))
+        .expect("Cannot increment ID counter");
+    Ok(id)
+}
+
+// Helper method to store a checked payload as a new product at `id`
+fn create_product(id: u64, mut payload: ProductPayload) -> Result<Product, Error> {
+    let initial_history = payload.initial_history.take();
+    let product = new_product(id, payload);
+    if let Some(existing) = do_insert(&product) {
+        // A fresh id must never be in use; put the existing record back
+        do_insert(&existing);
+        return Err(Error::Internal {
+            msg: format!("Refusing to overwrite existing product with id={}", id),
+        });
+    }
+    match initial_history {
+        Some(events) => {
+            for event in events {
+                append_status_event(id, event);
+            }
+        }
+        None => record_status_event(&product, product.timestamp),
+    }
+    record_audit(id, "created", String::new());
+    notify_registry(&product);
+    Ok(product)
+}
+
+// Helper method to keep `before` as the product's undo snapshot
+fn save_undo_snapshot(before: &Product) {
+    UNDO_SNAPSHOTS.with(|snapshots| snapshots.borrow_mut().insert(before.id, before.clone()));
+}
+
+// Helper method to enforce the configured status transitions. Keeping the
+// same status is always allowed; leaving a terminal status never is, short
+// of `reopen_product`.
+fn check_transition(from: &ProductStatus, to: &ProductStatus) -> Result<(), Error> {
+    if from == to {
+        return Ok(());
+    }
+    if is_terminal(from) {
+        return Err(Error::InvalidTransition {
+            from: from.clone(),
+            to: to.clone(),
+        });
+    }
+    if TRANSITIONS.with(|transitions| transitions.borrow().is_empty()) {
+        return Ok(());
+    }
+    let allowed = match (status_key(from), status_key(to)) {
+        (Ok(from), Ok(to)) => {
+            TRANSITIONS.with(|transitions| transitions.borrow().contains_key(&(from, to)))
+        }
+        _ => false,
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::InvalidTransition {
+            from: from.clone(),
+            to: to.clone(),
+        })
+    }
+}
+
+// Helper method to read the statuses products stay in once they reach them
+fn terminal_statuses() -> Vec<ProductStatus> {
+    match config().terminal_statuses {
+        Some(statuses) => statuses
+            .iter()
+            .map(|status| ProductStatus::parse(status))
+            .collect(),
+        None => vec![ProductStatus::Delivered],
+    }
+}
+
+// Helper method to check whether products stay in `status` once they reach it
+fn is_terminal(status: &ProductStatus) -> bool {
+    terminal_statuses().contains(status)
+}
+
+// Helper method to reject moving a product out of a terminal status to
+// `status` by any way other than `reopen_product`
+fn check_leaving_terminal(current: &Product, status: &ProductStatus) -> Result<(), Error> {
+    if current.status != *status && is_terminal(&current.status) {
+        return Err(Error::InvalidTransition {
+            from: current.status.clone(),
+            to: status.clone(),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to reject moving a product that is in a terminal status
+fn check_location_change(before: &Product, after: &Product) -> Result<(), Error> {
+    if is_terminal(&before.status) && before.current_location != after.current_location {
+        return Err(Error::InvalidTransition {
+            from: before.status.clone(),
+            to: after.status.clone(),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to use a status as a transition map key
+fn status_key(status: &ProductStatus) -> Result<StatusKey, Error> {
+    let status = status.clone().canonical();
+    if status.is_blank() {
+        return Err(Error::InvalidInput {
+            msg: "Status must not be empty".to_string(),
+        });
+    }
+    StatusKey::try_from(status.as_str().as_bytes()).map_err(|_| Error::InvalidInput {
+        msg: format!("Status '{}' is longer than 64 bytes", status),
+    })
+}
+
+// Helper method to use an origin as an allowlist key
+fn origin_key(origin: &str) -> Result<OriginKey, Error> {
+    let origin = origin.trim();
+    if origin.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Origin must not be empty".to_string(),
+        });
+    }
+    OriginKey::try_from(origin.as_bytes()).map_err(|_| Error::InvalidInput {
+        msg: format!("Origin '{}' is longer than 64 bytes", origin),
+    })
+}
+
+// Helper method to use an authority name as a map key
+fn authority_key(authority: &str) -> Result<AuthorityKey, Error> {
+    let authority = authority.trim();
+    if authority.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Certification authority must not be empty".to_string(),
+        });
+    }
+    AuthorityKey::try_from(authority.as_bytes()).map_err(|_| Error::InvalidInput {
+        msg: format!(
+            "Certification authority '{}' is longer than 64 bytes",
+            authority
+        ),
+    })
+}
+
+// Helper method to drop a verification that no longer applies because the
+// certification or its authority changed
+fn reset_cert_verification(before: &Product, after: &mut Product) {
+    if before.certification != after.certification || before.cert_authority != after.cert_authority
+    {
+        after.cert_verified = None;
+        after.cert_verified_at = None;
+    }
+}
+
+// Helper method to enforce the origin allowlist, if there is one
+fn check_origin_allowed(origin: &str) -> Result<(), Error> {
+    if ALLOWED_ORIGINS.with(|origins| origins.borrow().is_empty()) {
+        return Ok(());
+    }
+    let allowed = match origin_key(origin) {
+        Ok(key) => ALLOWED_ORIGINS.with(|origins| origins.borrow().contains_key(&key)),
+        Err(_) => false,
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: format!("Origin '{}' is not on the allowlist", origin),
+        })
+    }
+}
+
+// Helper method to turn a transition map key back into a status
+fn status_from_key(key: &StatusKey) -> ProductStatus {
+    ProductStatus::parse(&String::from_utf8_lossy(key.as_slice()))
+}
+
+// Helper method to reject a new product once its owner hit the quota
+fn check_owner_quota(owner: &Principal) -> Result<(), Error> {
+    let limit = match config().max_products_per_owner {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    if owner_count(owner) >= limit {
+        return Err(Error::QuotaExceeded { limit });
+    }
+    Ok(())
+}
+
+// Helper method to read an owner's number of non-archived products
+fn owner_count(owner: &Principal) -> u64 {
+    OWNER_COUNTS.with(|counts| counts.borrow().get(&principal_key(owner)).unwrap_or(0))
+}
+
+// Helper method to keep `TIMESTAMP_INDEX` in sync when a product is
+// replaced. Pass `None` for `before` on creation and for `after` on removal.
+fn update_timestamp_index(before: Option<&Product>, after: Option<&Product>) {
+    TIMESTAMP_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(before) = before {
+            index.remove(&(before.timestamp, before.id));
+        }
+        if let Some(after) = after {
+            index.insert((after.timestamp, after.id), ());
+        }
+    });
+}
+
+// Helper method to keep `OWNER_COUNTS` in sync when a product is replaced.
+// Pass `None` for `before` on creation and for `after` on removal.
+fn update_owner_counts(before: Option<&Product>, after: Option<&Product>) {
+    let counted = |product: Option<&Product>| {
+        product
+            .filter(|product| has_owner(product) && product.archived_at.is_none())
+            .and_then(|product| product.owner)
+    };
+    let (old, new) = (counted(before), counted(after));
+    if old == new {
+        return;
+    }
+    OWNER_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        if let Some(owner) = old {
+            let key = principal_key(&owner);
+            match counts.get(&key).unwrap_or(0) {
+                0 | 1 => counts.remove(&key),
+                n => counts.insert(key, n - 1),
+            };
+        }
+        if let Some(owner) = new {
+            let key = principal_key(&owner);
+            let n = counts.get(&key).unwrap_or(0);
+            counts.insert(key, n + 1);
+        }
+    });
+}
+
+// Helper method to use a principal as a stable map key
+fn principal_key(principal: &Principal) -> PrincipalKey {
+    PrincipalKey::try_from(principal.as_slice()).expect("Principal longer than 29 bytes")
+}
+
+// Helper method to read the configured maximum page size
+fn max_page_size() -> u64 {
+    config().max_page_size.unwrap_or(DEFAULT_MAX_PAGE_SIZE)
+}
+
+// Helper method to read the configured minimum search length
+fn min_search_length() -> u64 {
+    config()
+        .min_search_length
+        .unwrap_or(DEFAULT_MIN_SEARCH_LENGTH)
+}
+
+// Helper method to reject text queries too short to be selective
+fn check_search_query(query: &str) -> Result<(), Error> {
+    let min = min_search_length();
+    if (query.chars().count() as u64) < min {
+        return Err(Error::InvalidInput {
+            msg: format!("Search query must be at least {} characters long", min),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to clamp a requested page size
+fn page_limit(limit: u64) -> usize {
+    limit.min(max_page_size()) as usize
+}
+
+// Helper method to read the current settings
+fn config() -> Config {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+// Helper method to change and persist the settings
+fn update_config(change: impl FnOnce(&mut Config)) {
+    CONFIG.with(|cell| {
+        let mut config = cell.borrow().get().clone();
+        change(&mut config);
+        cell.borrow_mut()
+            .set(config)
+            .expect("Cannot update the config");
+    });
+}
+
+// Helper method to pick the status a product is counted under in
+// breakdowns. Blank statuses, e.g. from imported data, share one bucket.
+fn status_bucket(status: &ProductStatus) -> ProductStatus {
+    if status.is_blank() {
+        ProductStatus::Custom(UNKNOWN_STATUS.to_string())
+    } else {
+        status.clone()
+    }
+}
+
+// Helper method to check whether a product carries the recall status
+fn is_recalled(product: &Product) -> bool {
+    matches!(&product.status, ProductStatus::Custom(text) if text.eq_ignore_ascii_case(RECALLED_STATUS))
+}
+
+// Helper method to check whether an undelivered product missed its ETA
+fn is_overdue(product: &Product, now_ns: u64) -> bool {
+    product.archived_at.is_none()
+        && product.status != ProductStatus::Delivered
+        && product
+            .estimated_delivery_ns
+            .is_some_and(|eta| eta < now_ns)
+}
+
+// Helper method to check whether a product has a real owner
+fn has_owner(product: &Product) -> bool {
+    matches!(product.owner, Some(owner) if owner != Principal::anonymous())
+}
+
+// Helper method to restrict an endpoint to admins (the canister's controllers)
+fn ensure_admin() -> Result<(), Error> {
+    if is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only an admin can perform this action".to_string(),
+        })
+    }
+}
+
+// Helper method to check whether `principal` may modify the product: its
+// owner and admins may
+fn can_modify(product: &Product, principal: &Principal) -> bool {
+    product.owner == Some(*principal) || is_controller(principal)
+}
+
+// Helper method to restrict changes to the product's owner or an admin
+fn ensure_owner_or_admin(product: &Product) -> Result<(), Error> {
+    if can_modify(product, &caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!(
+                "Only the owner or an admin can modify product with id={}",
+                product.id
+            ),
+        })
+    }
+}
+
+// Helper method to drop export snapshots older than `EXPORT_TTL_NS`
+fn expire_exports(now: u64) {
+    let expired: Vec<u64> = EXPORT_TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .iter()
+            .filter(|(_, created_at)| now.saturating_sub(*created_at) > EXPORT_TTL_NS)
+            .map(|(token, _)| token)
+            .collect()
+    });
+    for token in expired {
+        EXPORT_TOKENS.with(|tokens| tokens.borrow_mut().remove(&token));
+        EXPORT_SNAPSHOTS.with(|snapshots| remove_prefixed(&mut snapshots.borrow_mut(), token));
+    }
+}
+
+// Helper method to build a fresh product record from a payload
+fn new_product(id: u64, payload: ProductPayload) -> Product {
+    Product {
+        id,
+        name: payload.name,
+        origin: payload.origin,
+        current_location: payload.current_location,
+        status: payload.status.canonical(),
+        certification: payload.certification,
+        timestamp: time(),
+        last_update: None,
+        iot_data: payload.iot_data,
+        created_by: Some(caller()),
+        owner: Some(caller()),
+        archived_at: None,
+        lot_id: None,
+        custodian: None,
+        weight_grams: payload.weight_grams,
+        dimensions_mm: payload.dimensions_mm,
+        latitude: payload.latitude,
+        longitude: payload.longitude,
+        tracking_code: payload.tracking_code,
+        parent_id: None,
+        estimated_delivery_ns: payload.estimated_delivery_ns,
+        field_updated_at: None,
+        enrichment: None,
+        priority: payload.priority,
+        sla_deadline_ns: payload.sla_deadline_ns,
+        tags: None,
+        declared_value_cents: payload.declared_value_cents,
+        currency: payload.currency,
+        metadata: None,
+        cert_authority: payload.cert_authority,
+        cert_verified: None,
+        cert_verified_at: None,
+        cert_expires_at: payload.cert_expires_at,
+        is_test: payload.is_test.filter(|&is_test| is_test),
+        last_modified_by: Some(caller()),
+    }
+}
+
+// Helper method to validate a payload before it is stored
+fn validate_product_payload(payload: &ProductPayload) -> Result<(), Error> {
+    let mut candidate = new_product(0, payload.clone());
+    candidate.last_update = Some(candidate.timestamp);
+    validate_product(&candidate)
+}
+
+// Helper method to validate a product record before it is stored
+fn validate_product(product: &Product) -> Result<(), Error> {
+    let required = [
+        ("name", product.name.as_str()),
+        ("origin", product.origin.as_str()),
+        ("current_location", product.current_location.as_str()),
+        ("status", product.status.as_str()),
+    ];
+    for (field, value) in required {
+        if value.trim().is_empty() {
+            return Err(Error::InvalidInput {
+                msg: format!("Field '{}' must not be empty", field),
+            });
+        }
+    }
+
+    if let Some((length, width, height)) = product.dimensions_mm {
+        if length == 0 || width == 0 || height == 0 {
+            return Err(Error::InvalidInput {
+                msg: "All dimensions must be greater than zero".to_string(),
+            });
+        }
+    }
+
+    if product
+        .priority
+        .is_some_and(|priority| !PRIORITY_RANGE.contains(&priority))
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Priority must be between {} and {}",
+                PRIORITY_RANGE.start(),
+                PRIORITY_RANGE.end()
+            ),
+        });
+    }
+    if let Some(currency) = &product.currency {
+        if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Currency '{}' is not a 3-letter ISO code such as \"USD\"",
+                    currency
+                ),
+            });
+        }
+    }
+    if let Some(authority) = &product.cert_authority {
+        authority_key(authority)?;
+    }
+    if product.declared_value_cents.is_some() && product.currency.is_none() {
+        return Err(Error::InvalidInput {
+            msg: "A declared value needs a currency".to_string(),
+        });
+    }
+    validate_coordinates(product.latitude, product.longitude)?;
+    if let Some(code) = &product.tracking_code {
+        validate_tracking_code(code)?;
+    }
+
+    check_size(product)
+}
+
+// Helper method to check a tracking code's format. Codes may not be purely
+// numeric so that `lookup_product` can tell them apart from ids.
+fn validate_tracking_code(code: &str) -> Result<(), Error> {
+    let valid = !code.is_empty()
+        && code.chars().count() <= MAX_TRACKING_CODE_LEN
+        && code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && code.parse::<u64>().is_err();
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: format!(
+                "Tracking code must be 1 to {} letters, digits or dashes and not a number",
+                MAX_TRACKING_CODE_LEN
+            ),
+        })
+    }
+}
+
+// Helper method to reject a tracking code that is already in use
+fn check_tracking_code_free(code: &Option<String>) -> Result<(), Error> {
+    match code {
+        Some(code) if find_by_tracking_code(code).is_some() => Err(Error::InvalidInput {
+            msg: format!("Tracking code '{}' is already in use", code),
         }),
+        _ => Ok(()),
     }
 }
 
-// Delete a product entry by ID
-#[ic_cdk::update]
-fn delete_product(id: u64) -> Result<Product, Error> {
-    match PRODUCT_STORAGE.with(|storage| storage.borrow_mut().remove(&id)) {
-        Some(product) => Ok(product),
-        None => Err(Error::NotFound {
-            msg: format!("Cannot delete product with id={}. Product not found.", id),
+// Helper method to find a product by its tracking code
+fn find_by_tracking_code(code: &str) -> Option<Product> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .find(|product| product.tracking_code.as_deref() == Some(code))
+    })
+}
+
+// Helper method to reject an ETA or SLA deadline before the product was
+// created
+fn check_deadlines(eta: Option<u64>, sla: Option<u64>, created: u64) -> Result<(), Error> {
+    if eta.is_some_and(|eta| eta < created) {
+        return Err(Error::InvalidInput {
+            msg: "Estimated delivery must not be before the product's creation".to_string(),
+        });
+    }
+    if sla.is_some_and(|sla| sla < created) {
+        return Err(Error::InvalidInput {
+            msg: "SLA deadline must not be before the product's creation".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to compute the Levenshtein distance between two strings
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Helper method to compute the great-circle distance in metres between two
+// points given in degrees
+fn haversine_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+// Helper method to check that coordinates come in valid pairs
+fn validate_coordinates(latitude: Option<f64>, longitude: Option<f64>) -> Result<(), Error> {
+    match (latitude, longitude) {
+        (None, None) => Ok(()),
+        (Some(lat), Some(lon)) => {
+            if !(-90.0..=90.0).contains(&lat) {
+                Err(Error::InvalidInput {
+                    msg: format!("Latitude {} is outside -90..=90", lat),
+                })
+            } else if !(-180.0..=180.0).contains(&lon) {
+                Err(Error::InvalidInput {
+                    msg: format!("Longitude {} is outside -180..=180", lon),
+                })
+            } else {
+                Ok(())
+            }
+        }
+        _ => Err(Error::InvalidInput {
+            msg: "Latitude and longitude must be given together".to_string(),
         }),
     }
 }
 
-// Helper method for inserting a product into storage
-fn do_insert(product: &Product) {
-    PRODUCT_STORAGE.with(|storage| storage.borrow_mut().insert(product.id, product.clone()));
+// Helper method to check for a SHA-256 digest written as 64 hex characters
+fn validate_sha256_hex(hash: &str) -> Result<(), Error> {
+    if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: "Hash must be a SHA-256 digest of 64 hex characters".to_string(),
+        })
+    }
+}
+
+// Helper method to reject records that would not fit in stable storage
+fn check_size(product: &Product) -> Result<(), Error> {
+    let size = product.to_bytes().len();
+    if size > Product::MAX_SIZE as usize {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Product is {} bytes encoded, exceeding the limit of {} bytes",
+                size,
+                Product::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper method to check a product against a filter. Every filtering query
+// goes through here so results and counts always agree.
+fn matches_filter(product: &Product, filter: &ProductFilter) -> bool {
+    fn matches<T: PartialEq>(wanted: &Option<T>, actual: &T) -> bool {
+        wanted.as_ref().is_none_or(|wanted| wanted == actual)
+    }
+
+    matches(
+        &filter.status.clone().map(ProductStatus::canonical),
+        &product.status,
+    ) && matches(&filter.origin, &product.origin)
+        && filter.current_location.as_ref().is_none_or(|wanted| {
+            normalize_location(wanted) == normalize_location(&product.current_location)
+        })
+        && (filter.owner.is_none() || filter.owner == product.owner)
+        && (filter.lot_id.is_none() || filter.lot_id == product.lot_id)
+        && filter
+            .created_after
+            .is_none_or(|after| product.timestamp >= after)
+        && filter
+            .created_before
+            .is_none_or(|before| product.timestamp < before)
+        && (filter.include_archived.unwrap_or(false) || product.archived_at.is_none())
+        && is_included(product, filter.include_test)
+}
+
+// Helper method to bring a location into its canonical form
+fn normalize_location(location: &str) -> String {
+    location
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+// Helper method to leave test products out of results unless `include_test`
+// is set. Lookups of given products (by id, tracking code or hierarchy) and
+// their records, the change feeds, the `begin_export` backup,
+// `products_with_unknown_status` and the owner quota see test products
+// regardless, since they must cover every stored record.
+fn is_included(product: &Product, include_test: Option<bool>) -> bool {
+    include_test.unwrap_or(false) || !product.is_test.unwrap_or(false)
+}
+
+// Helper method to collect the ids of test products
+fn test_product_ids() -> BTreeSet<u64> {
+    PRODUCT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, product)| product.is_test.unwrap_or(false))
+            .map(|(id, _)| id)
+            .collect()
+    })
+}
+
+// Helper method to follow a dotted path through JSON objects (and arrays,
+// using numeric segments)
+fn json_at_path<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.')
+        .try_fold(value, |value, segment| match value {
+            serde_json::Value::Object(map) => map.get(segment),
+            serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+// Helper method to fingerprint a product's stored representation
+fn fingerprint(product: &Product) -> String {
+    hex::encode(Sha256::digest(product.to_bytes()))
+}
+
+// Helper method behind `patch_product`
+fn do_patch(id: u64, patch: ProductPatch) -> Result<Product, Error> {
+    let before = _get_product(&id).ok_or_else(|| Error::NotFound {
+        msg: format!("Cannot patch product with id={}. Product not found", id),
+    })?;
+    ensure_owner_or_admin(&before)?;
+
+    let product = apply_patch(&before, patch, time())?;
+    save_undo_snapshot(&before);
+    do_insert(&product);
+    if product.status != before.status {
+        record_status_event(&product, time());
+    }
+    record_audit(id, "patched", String::new());
+    Ok(product)
+}
+
+// Helper method to merge a patch into a copy of `before` and validate the
+// result as a whole, leaving the stored product alone
+fn apply_patch(before: &Product, patch: ProductPatch, now: u64) -> Result<Product, Error> {
+    let mut product = before.clone();
+    if let Some(name) = patch.name {
+        product.name = name;
+    }
+    if let Some(origin) = patch.origin {
+        product.origin = origin;
+    }
+    if let Some(location) = patch.current_location {
+        product.current_location = location;
+    }
+    if let Some(status) = patch.status {
+        product.status = status.canonical();
+    }
+    if let Some(certification) = patch.certification {
+        product.certification = Some(certification);
+    }
+    if let Some(iot_data) = patch.iot_data {
+        product.iot_data = Some(iot_data);
+    }
+    if let Some(weight) = patch.weight_grams {
+        product.weight_grams = Some(weight);
+    }
+    if let Some(dimensions) = patch.dimensions_mm {
+        product.dimensions_mm = Some(dimensions);
+    }
+    if let Some(latitude) = patch.latitude {
+        product.latitude = Some(latitude);
+    }
+    if let Some(longitude) = patch.longitude {
+        product.longitude = Some(longitude);
+    }
+    if let Some(eta) = patch.estimated_delivery_ns {
+        product.estimated_delivery_ns = Some(eta);
+    }
+    if let Some(priority) = patch.priority {
+        product.priority = Some(priority);
+    }
+    if let Some(deadline) = patch.sla_deadline_ns {
+        product.sla_deadline_ns = Some(deadline);
+    }
+    if let Some(value) = patch.declared_value_cents {
+        product.declared_value_cents = Some(value);
+    }
+    if let Some(currency) = patch.currency {
+        product.currency = Some(currency);
+    }
+    if let Some(authority) = patch.cert_authority {
+        product.cert_authority = Some(authority);
+    }
+    if let Some(expires_at) = patch.cert_expires_at {
+        product.cert_expires_at = Some(expires_at);
+    }
+    product.last_update = Some(now);
+    product.last_modified_by = Some(caller());
+    stamp_changed_fields(before, &mut product, now);
+    reset_cert_verification(before, &mut product);
+
+    validate_product(&product)?;
+    if product.origin != before.origin {
+        check_origin_allowed(&product.origin)?;
+    }
+    check_transition(&before.status, &product.status)?;
+    check_location_change(before, &product)?;
+    check_deadlines(
+        product.estimated_delivery_ns,
+        product.sla_deadline_ns,
+        product.timestamp,
+    )?;
+    Ok(product)
+}
+
+// Helper method to render the comparable fields of a product as plain text,
+// in a fixed order. Unset fields are empty.
+fn field_values(product: &Product) -> Vec<(&'static str, String)> {
+    fn text<T: std::fmt::Display>(value: &Option<T>) -> String {
+        value
+            .as_ref()
+            .map_or(String::new(), |value| value.to_string())
+    }
+
+    vec![
+        ("name", product.name.clone()),
+        ("origin", product.origin.clone()),
+        ("current_location", product.current_location.clone()),
+        ("status", product.status.to_string()),
+        ("certification", text(&product.certification)),
+        ("iot_data", text(&product.iot_data)),
+        ("weight_grams", text(&product.weight_grams)),
+        (
+            "dimensions_mm",
+            product
+                .dimensions_mm
+                .map_or(String::new(), |(length, width, height)| {
+                    format!("{}x{}x{}", length, width, height)
+                }),
+        ),
+        ("latitude", text(&product.latitude)),
+        ("longitude", text(&product.longitude)),
+        (
+            "estimated_delivery_ns",
+            text(&product.estimated_delivery_ns),
+        ),
+        ("priority", text(&product.priority)),
+        ("sla_deadline_ns", text(&product.sla_deadline_ns)),
+        ("declared_value_cents", text(&product.declared_value_cents)),
+        ("currency", text(&product.currency)),
+        ("cert_authority", text(&product.cert_authority)),
+        ("cert_expires_at", text(&product.cert_expires_at)),
+    ]
+}
+
+// Helper method to record `now` in `field_updated_at` for every field that
+// differs between `before` and `after`
+fn stamp_changed_fields(before: &Product, after: &mut Product, now: u64) {
+    let mut stamps = after.field_updated_at.take().unwrap_or_default();
+    for ((field, old), (_, new)) in field_values(before).into_iter().zip(field_values(after)) {
+        if old == new {
+            continue;
+        }
+        match stamps.iter_mut().find(|(name, _)| name == field) {
+            Some((_, at)) => *at = now,
+            None => stamps.push((field.to_string(), now)),
+        }
+    }
+    after.field_updated_at = if stamps.is_empty() {
+        None
+    } else {
+        Some(stamps)
+    };
+}
+
+// Helper method to render a product as one CSV row, including the line break
+fn csv_row(product: &Product) -> String {
+    let fields = [
+        product.id.to_string(),
+        csv_field(&product.name),
+        csv_field(&product.origin),
+        csv_field(&product.current_location),
+        csv_field(product.status.as_str()),
+        product.timestamp.to_string(),
+        product
+            .last_update
+            .map(|t| t.to_string())
+            .unwrap_or_default(),
+    ];
+    format!("{}\r\n", fields.join(","))
+}
+
+// Helper method to quote a CSV field when it contains a delimiter, quote or
+// line break, doubling any embedded quotes
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Helper method for inserting a product into storage. Returns the record it
+// replaced, if any.
+fn do_insert(product: &Product) -> Option<Product> {
+    let previous =
+        PRODUCT_STORAGE.with(|storage| storage.borrow_mut().insert(product.id, product.clone()));
+    update_owner_counts(previous.as_ref(), Some(product));
+    update_timestamp_index(previous.as_ref(), Some(product));
+    previous
 }
 
 // Helper method to retrieve a product by ID
@@ -142,11 +5176,958 @@ fn _get_product(id: &u64) -> Option<Product> {
     PRODUCT_STORAGE.with(|storage| storage.borrow().get(id))
 }
 
+// Helper method to append the product's current status to its history
+fn record_status_event(product: &Product, timestamp: u64) {
+    let event = StatusEvent {
+        status: product.status.clone(),
+        location: product.current_location.clone(),
+        timestamp,
+    };
+    append_status_event(product.id, event);
+}
+
+// Helper method to append an event to a product's history
+fn append_status_event(id: u64, event: StatusEvent) {
+    STATUS_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let next = history
+            .range((id, 0)..=(id, u64::MAX))
+            .last()
+            .map_or(0, |((_, seq), _)| seq + 1);
+        history.insert((id, next), event);
+    });
+}
+
+// Helper method to check imported history: non-empty, at most
+// `MAX_INITIAL_HISTORY_LEN` events in timestamp order, all before `now`.
+// Statuses are normalised in place.
+fn check_initial_history(events: &mut [StatusEvent], now: u64) -> Result<(), Error> {
+    if events.is_empty() || events.len() > MAX_INITIAL_HISTORY_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Initial history must have 1 to {} events",
+                MAX_INITIAL_HISTORY_LEN
+            ),
+        });
+    }
+    if events
+        .windows(2)
+        .any(|pair| pair[1].timestamp < pair[0].timestamp)
+    {
+        return Err(Error::InvalidInput {
+            msg: "Initial history must be in timestamp order".to_string(),
+        });
+    }
+    for event in events.iter_mut() {
+        event.status = event.status.clone().canonical();
+        if event.status.is_blank() {
+            return Err(Error::InvalidInput {
+                msg: "Initial history statuses must not be empty".to_string(),
+            });
+        }
+        if event.timestamp >= now {
+            return Err(Error::InvalidInput {
+                msg: "Initial history events must be in the past".to_string(),
+            });
+        }
+        if event.to_bytes().len() > StatusEvent::MAX_SIZE as usize {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Initial history event exceeds the limit of {} bytes",
+                    StatusEvent::MAX_SIZE
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Helper method to retrieve a product's status history, oldest first
+fn get_history(id: u64) -> Vec<StatusEvent> {
+    STATUS_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(_, event)| event)
+            .collect()
+    })
+}
+
+// Helper method to find when a product entered transit for the last time:
+// the first event of the run of in-transit events its history ends with
+fn transit_started_at(product: &Product) -> Option<u64> {
+    get_history(product.id)
+        .iter()
+        .rev()
+        .take_while(|event| event.status == ProductStatus::InTransit)
+        .last()
+        .map(|event| event.timestamp)
+}
+
+// Helper method to drop everything stored alongside a deleted product
+fn clear_product_records(id: u64) {
+    STATUS_HISTORY.with(|history| remove_prefixed(&mut history.borrow_mut(), id));
+    DOCUMENTS.with(|documents| remove_prefixed(&mut documents.borrow_mut(), id));
+    PHOTOS.with(|photos| remove_prefixed(&mut photos.borrow_mut(), id));
+    NOTES.with(|notes| remove_prefixed(&mut notes.borrow_mut(), id));
+    UNDO_SNAPSHOTS.with(|snapshots| snapshots.borrow_mut().remove(&id));
+}
+
+// Helper method to remove every `(prefix, _)` entry of a map
+fn remove_prefixed<V: BoundedStorable>(
+    map: &mut StableBTreeMap<(u64, u64), V, Memory>,
+    prefix: u64,
+) {
+    let keys: Vec<(u64, u64)> = map
+        .range((prefix, 0)..=(prefix, u64::MAX))
+        .map(|(key, _)| key)
+        .collect();
+    for key in keys {
+        map.remove(&key);
+    }
+}
+
+// Helper method to append an event to the audit log on behalf of the caller
+fn record_audit(product_id: u64, action: &str, detail: String) {
+    record_audit_by(caller(), product_id, action, detail);
+}
+
+// Helper method to append an audit event on behalf of `actor`. Timer
+// callbacks have no caller, so they record the canister itself.
+fn record_audit_by(actor: Principal, product_id: u64, action: &str, mut detail: String) {
+    if detail.len() > MAX_AUDIT_DETAIL_LEN {
+        let mut end = MAX_AUDIT_DETAIL_LEN;
+        while !detail.is_char_boundary(end) {
+            end -= 1;
+        }
+        detail.truncate(end);
+    }
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let seq = log.last_key_value().map_or(1, |(seq, _)| seq + 1);
+        let event = AuditEvent {
+            seq,
+            product_id,
+            action: action.to_string(),
+            caller: actor,
+            timestamp: time(),
+            detail,
+        };
+        log.insert(seq, event);
+    });
+    record_change(product_id);
+}
+
+// Helper method to bump the change sequence and remember which product
+// changed
+fn record_change(product_id: u64) {
+    let seq = CHANGE_SEQ.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let seq = *counter.get() + 1;
+        counter
+            .set(seq)
+            .expect("Cannot increment the change sequence");
+        seq
+    });
+    CHANGE_RING.with(|ring| {
+        ring.borrow_mut()
+            .insert(seq % CHANGE_RING_SIZE, (seq, product_id))
+    });
+}
+
+// Helper method to show an optional principal in audit details
+fn describe_principal(principal: Option<Principal>) -> String {
+    principal.map_or_else(|| "(none)".to_string(), |p| p.to_text())
+}
+
 // Custom error handling
 #[derive(candid::CandidType, Deserialize, Serialize)]
 enum Error {
-    NotFound { msg: String },
+    NotFound {
+        msg: String,
+    },
+    InvalidInput {
+        msg: String,
+    },
+    Unauthorized {
+        msg: String,
+    },
+    QuotaExceeded {
+        limit: u64,
+    },
+    InvalidTransition {
+        from: ProductStatus,
+        to: ProductStatus,
+    },
+    Internal {
+        msg: String,
+    },
+    RemoteCallFailed {
+        msg: String,
+    },
+    Conflict {
+        msg: String,
+    },
 }
 
 // Candid export for interface generation
 ic_cdk::export_candid!();
+
+// Stand-ins for the system API, which is only available on a canister, so the
+// logic can be unit tested natively
+#[cfg(test)]
+mod test_env {
+    use candid::Principal;
+    use std::cell::{Cell, RefCell};
+
+    thread_local! {
+        static NOW: Cell<u64> = const { Cell::new(1_000_000_000) };
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        static CONTROLLERS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub fn time() -> u64 {
+        NOW.with(|now| now.get())
+    }
+
+    pub fn caller() -> Principal {
+        CALLER.with(|caller| caller.get())
+    }
+
+    pub fn print<S: AsRef<str>>(message: S) {
+        eprintln!("{}", message.as_ref());
+    }
+
+    pub fn is_controller(principal: &Principal) -> bool {
+        CONTROLLERS.with(|controllers| controllers.borrow().contains(principal))
+    }
+
+    // Helper method to move the clock forward by `ns`
+    pub fn advance_time(ns: u64) {
+        NOW.with(|now| now.set(now.get() + ns));
+    }
+
+    // Helper method to make the following calls on behalf of `principal`
+    pub fn set_caller(principal: Principal) {
+        CALLER.with(|caller| caller.set(principal));
+    }
+
+    // Helper method to make `principal` an admin
+    pub fn add_controller(principal: Principal) {
+        CONTROLLERS.with(|controllers| controllers.borrow_mut().push(principal));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_env::{add_controller, advance_time, set_caller};
+
+    // Every test runs on its own thread, so it starts from empty storage
+    fn setup() {
+        set_stored_schema_version(SCHEMA_VERSION);
+        add_controller(admin());
+    }
+
+    fn admin() -> Principal {
+        Principal::from_slice(&[10])
+    }
+
+    fn alice() -> Principal {
+        Principal::from_slice(&[11])
+    }
+
+    fn bob() -> Principal {
+        Principal::from_slice(&[12])
+    }
+
+    fn carol() -> Principal {
+        Principal::from_slice(&[13])
+    }
+
+    fn payload(name: &str) -> ProductPayload {
+        ProductPayload {
+            name: name.to_string(),
+            origin: "Kenya".to_string(),
+            current_location: "Nairobi".to_string(),
+            status: ProductStatus::Manufactured,
+            ..Default::default()
+        }
+    }
+
+    fn add_as(principal: Principal, name: &str) -> Product {
+        set_caller(principal);
+        advance_time(1);
+        add_product(payload(name)).ok().expect("Cannot add product")
+    }
+
+    // Non-archived products per owner, counted from the primary map
+    fn scanned_owner_counts() -> BTreeMap<Principal, u64> {
+        let mut counts = BTreeMap::new();
+        PRODUCT_STORAGE.with(|storage| {
+            for (_, product) in storage.borrow().iter() {
+                if has_owner(&product) && product.archived_at.is_none() {
+                    *counts.entry(product.owner.unwrap()).or_insert(0) += 1;
+                }
+            }
+        });
+        counts
+    }
+
+    fn stored_owner_counts() -> BTreeMap<Principal, u64> {
+        set_caller(admin());
+        list_owners_with_counts(Some(true))
+            .ok()
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn owner_counts_match_a_scan() {
+        setup();
+        let first = add_as(alice(), "Coffee");
+        let second = add_as(alice(), "Tea");
+        let third = add_as(alice(), "Cocoa");
+        add_as(bob(), "Vanilla");
+        assert_eq!(stored_owner_counts(), scanned_owner_counts());
+
+        set_caller(admin());
+        assert!(assign_owner(first.id, bob()).is_ok());
+        set_caller(alice());
+        assert!(archive_product(second.id).is_ok());
+        assert!(delete_product(third.id).is_ok());
+        set_caller(admin());
+        assert_eq!(transfer_all_ownership(bob(), carol()).ok(), Some(2));
+        set_caller(carol());
+        assert!(archive_product(first.id).is_ok());
+        assert!(unarchive_product(first.id).is_ok());
+
+        let counts = stored_owner_counts();
+        assert_eq!(counts, scanned_owner_counts());
+        assert_eq!(counts.get(&carol()), Some(&2));
+        assert_eq!(counts.get(&alice()), None);
+    }
+
+    #[test]
+    fn validate_payload_checks_initial_history() {
+        setup();
+        set_caller(alice());
+        let event = |timestamp| StatusEvent {
+            status: ProductStatus::Manufactured,
+            location: "Nairobi".to_string(),
+            timestamp,
+        };
+        let mut unordered = payload("Coffee");
+        unordered.initial_history = Some(vec![event(20), event(10)]);
+        let mut future = payload("Coffee");
+        future.initial_history = Some(vec![event(time() + 1)]);
+        let mut current = payload("Coffee");
+        current.initial_history = Some(vec![event(time())]);
+        for payload in [unordered, future, current] {
+            assert!(matches!(
+                validate_payload(payload.clone()),
+                Err(Error::InvalidInput { .. })
+            ));
+            assert!(matches!(
+                add_product(payload),
+                Err(Error::InvalidInput { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn validate_payload_applies_default_status() {
+        setup();
+        let mut blank = payload("Coffee");
+        blank.status = ProductStatus::default();
+        set_caller(alice());
+        assert!(validate_payload(blank.clone()).is_err());
+
+        set_caller(admin());
+        assert!(set_default_status(Some(ProductStatus::Manufactured)).is_ok());
+        set_caller(alice());
+        assert!(validate_payload(blank.clone()).is_ok());
+        let product = add_product(blank).ok().unwrap();
+        assert_eq!(product.status, ProductStatus::Manufactured);
+    }
+
+    #[test]
+    fn set_parent_refuses_to_close_a_loop() {
+        setup();
+        let top = add_as(alice(), "Pallet");
+        let middle = add_as(alice(), "Crate");
+        let bottom = add_as(alice(), "Box");
+        assert!(set_parent(middle.id, Some(top.id)).is_ok());
+        assert!(set_parent(bottom.id, Some(middle.id)).is_ok());
+
+        for parent in [bottom.id, middle.id, top.id] {
+            assert!(matches!(
+                set_parent(top.id, Some(parent)),
+                Err(Error::InvalidInput { .. })
+            ));
+        }
+        assert_eq!(_get_product(&top.id).unwrap().parent_id, None);
+        assert_eq!(ancestor_ids(bottom.id), vec![middle.id, top.id]);
+    }
+
+    #[test]
+    fn deleting_a_parent_records_its_children() {
+        setup();
+        let parent = add_as(alice(), "Pallet");
+        let child = add_as(alice(), "Box");
+        assert!(set_parent(child.id, Some(parent.id)).is_ok());
+        let seq = current_change_seq();
+
+        set_caller(admin());
+        assert!(delete_product(parent.id).is_ok());
+        let child = _get_product(&child.id).unwrap();
+        assert_eq!(child.parent_id, None);
+        assert_eq!(child.last_modified_by, Some(admin()));
+        assert_eq!(changed_product_ids_since(seq), vec![child.id, parent.id]);
+        let last = get_audit_log(child.id).pop().unwrap();
+        assert_eq!(last.action, "parent_changed");
+    }
+
+    // A product as stored by the initial release
+    #[derive(candid::CandidType)]
+    struct InitialProduct {
+        id: u64,
+        name: String,
+        origin: String,
+        current_location: String,
+        status: String,
+        certification: Option<String>,
+        timestamp: u64,
+        last_update: Option<u64>,
+        iot_data: Option<String>,
+    }
+
+    #[test]
+    fn old_records_decode_with_defaults_and_migrate() {
+        setup();
+        let old = InitialProduct {
+            id: 7,
+            name: "Coffee".to_string(),
+            origin: "Kenya".to_string(),
+            current_location: "Nairobi".to_string(),
+            status: "In Transit".to_string(),
+            certification: None,
+            timestamp: 5,
+            last_update: None,
+            iot_data: None,
+        };
+        let product = Product::from_bytes(Cow::Owned(Encode!(&old).unwrap()));
+        assert_eq!(product.id, 7);
+        assert_eq!(product.status, ProductStatus::InTransit);
+        assert_eq!(product.created_by, None);
+        assert_eq!(product.owner, None);
+        assert_eq!(product.tags, None);
+        assert_eq!(product.archived_at, None);
+        do_insert(&product);
+        // A later record that tracked its creator but not yet its owner
+        let mut created = product.clone();
+        created.id = 8;
+        created.created_by = Some(alice());
+        do_insert(&created);
+
+        set_stored_schema_version(SCHEMA_VERSION - 1);
+        set_caller(alice());
+        assert!(add_product(payload("Tea")).is_err());
+        set_caller(admin());
+        assert_eq!(migrate_products().ok(), Some(2));
+        assert!(ensure_migrated().is_ok());
+        let initial = _get_product(&7).unwrap();
+        assert_eq!((initial.created_by, initial.owner), (None, None));
+        assert_eq!(_get_product(&8).unwrap().owner, Some(alice()));
+
+        // Nobody owns the initial record, so it waits for an admin
+        let unassigned: Vec<u64> = unassigned_products(None)
+            .iter()
+            .map(|product| product.id)
+            .collect();
+        assert_eq!(unassigned, vec![7]);
+        set_caller(alice());
+        assert!(touch_product(7).is_err());
+        set_caller(admin());
+        assert!(assign_owner(7, bob()).is_ok());
+        assert!(unassigned_products(None).is_empty());
+    }
+
+    #[test]
+    fn id_collisions_never_overwrite() {
+        setup();
+        let existing = add_as(alice(), "Coffee");
+
+        // A corrupted counter pointing back at a used id
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(existing.id).unwrap());
+        set_caller(bob());
+        assert!(matches!(
+            add_product(payload("Tea")),
+            Err(Error::Internal { .. })
+        ));
+        assert_eq!(
+            ID_COUNTER.with(|counter| *counter.borrow().get()),
+            existing.id
+        );
+
+        // A record that appeared at a reserved id
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(existing.id + 1).unwrap());
+        let reserved = reserve_product_id().ok().unwrap();
+        let mut squatter = existing.clone();
+        squatter.id = reserved;
+        do_insert(&squatter);
+        assert!(matches!(
+            finalize_reserved(reserved, payload("Tea")),
+            Err(Error::Internal { .. })
+        ));
+
+        assert_eq!(_get_product(&existing.id).unwrap().name, "Coffee");
+        assert_eq!(_get_product(&reserved).unwrap().name, "Coffee");
+        assert_eq!(owner_count(&alice()), 2);
+        assert_eq!(owner_count(&bob()), 0);
+    }
+
+    #[test]
+    fn rejected_patch_changes_nothing() {
+        setup();
+        let product = add_as(alice(), "Coffee");
+        let seq = current_change_seq();
+        let patch = ProductPatch {
+            name: Some("Arabica".to_string()),
+            latitude: Some(91.0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            patch_product(product.id, patch),
+            Err(Error::InvalidInput { .. })
+        ));
+
+        let stored = _get_product(&product.id).unwrap();
+        assert_eq!(stored.name, "Coffee");
+        assert_eq!(stored.latitude, None);
+        assert_eq!(stored.last_update, None);
+        assert_eq!(current_change_seq(), seq);
+    }
+
+    // Ids of every stored product in creation order, from the primary map
+    fn scanned_creation_order() -> Vec<u64> {
+        let mut created: Vec<(u64, u64)> = PRODUCT_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(id, product)| (product.timestamp, id))
+                .collect()
+        });
+        created.sort_unstable();
+        created.into_iter().map(|(_, id)| id).collect()
+    }
+
+    fn assert_index_consistent() {
+        assert!(verify_timestamp_index());
+        let listed: Vec<u64> = list_by_timestamp_after(None, None, 1000, Some(true))
+            .ok()
+            .unwrap()
+            .items
+            .iter()
+            .map(|product| product.id)
+            .collect();
+        assert_eq!(listed, scanned_creation_order());
+    }
+
+    #[test]
+    fn timestamp_index_matches_products_after_mixed_operations() {
+        setup();
+        let first = add_as(alice(), "Coffee");
+        let second = add_as(alice(), "Tea");
+        let mut test_payload = payload("Sample");
+        test_payload.is_test = Some(true);
+        set_caller(bob());
+        assert!(add_product(test_payload).is_ok());
+        assert_index_consistent();
+
+        set_caller(alice());
+        let mut moved = payload("Coffee");
+        moved.status = ProductStatus::InTransit;
+        assert!(update_product(first.id, moved).is_ok());
+        assert!(set_parent(second.id, Some(first.id)).is_ok());
+        assert!(archive_product(second.id).is_ok());
+        assert_index_consistent();
+
+        assert!(delete_product(first.id).is_ok());
+        set_caller(admin());
+        assert!(purge_test_products().is_ok());
+        let mut imported = second.clone();
+        imported.id = 100;
+        imported.parent_id = None;
+        imported.archived_at = None;
+        imported.timestamp = 1;
+        assert_eq!(import_products_with_ids(vec![imported]).ok(), Some(1));
+        assert_index_consistent();
+        assert_eq!(scanned_creation_order(), vec![100, second.id]);
+    }
+
+    #[test]
+    fn index_queries_fail_until_migrated() {
+        setup();
+        add_as(alice(), "Coffee");
+        add_as(alice(), "Tea");
+        // An index left empty by a version before it existed
+        TIMESTAMP_INDEX.with(|index| {
+            let keys: Vec<(u64, u64)> = index.borrow().iter().map(|(key, _)| key).collect();
+            let mut index = index.borrow_mut();
+            for key in keys {
+                index.remove(&key);
+            }
+        });
+        set_stored_schema_version(2);
+        assert!(list_by_timestamp_after(None, None, 10, None).is_err());
+        assert!(oldest_undelivered(None).is_err());
+        assert!(creation_histogram(1, 0, 10, None).is_err());
+        assert!(products_by_creator_between(alice(), 0, u64::MAX, None).is_err());
+
+        set_caller(admin());
+        assert_eq!(migrate_products().ok(), Some(2));
+        assert_index_consistent();
+        assert!(oldest_undelivered(None).ok().unwrap().is_some());
+    }
+
+    #[test]
+    fn undo_stamps_the_restored_fields() {
+        setup();
+        let product = add_as(alice(), "Coffee");
+        let mut moved = payload("Coffee");
+        moved.status = ProductStatus::InTransit;
+        assert!(update_product(product.id, moved).is_ok());
+
+        advance_time(10);
+        assert!(undo_last_update(product.id).is_ok());
+        let restored = _get_product(&product.id).unwrap();
+        assert_eq!(restored.status, ProductStatus::Manufactured);
+        let stamps = restored.field_updated_at.unwrap();
+        assert!(stamps.contains(&("status".to_string(), time())));
+    }
+
+    #[test]
+    fn undo_refuses_to_outgrow_the_size_limit() {
+        setup();
+        let product = add_as(alice(), "Coffee");
+        let base = product.to_bytes().len();
+        let mut large = payload("Coffee");
+        large.iot_data = Some("x".repeat(Product::MAX_SIZE as usize - base - 200));
+        assert!(update_product(product.id, large).is_ok());
+        assert!(update_product(product.id, payload("Coffee")).is_ok());
+        assert!(set_metadata(product.id, "notes".to_string(), "y".repeat(400)).is_ok());
+
+        assert!(matches!(
+            undo_last_update(product.id),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert_eq!(_get_product(&product.id).unwrap().iot_data, None);
+        assert!(UNDO_SNAPSHOTS.with(|snapshots| snapshots.borrow().contains_key(&product.id)));
+    }
+
+    #[test]
+    fn growing_a_full_record_is_rejected() {
+        setup();
+        let product = add_as(alice(), "Coffee");
+        let parent = add_as(alice(), "Pallet");
+        // Fill the record up to the limit
+        let mut space = Product::MAX_SIZE as usize - product.to_bytes().len();
+        loop {
+            let mut full = payload("Coffee");
+            full.iot_data = Some("x".repeat(space));
+            if update_product(product.id, full).is_ok() {
+                break;
+            }
+            space -= 1;
+        }
+
+        let too_large =
+            |result: Result<Product, Error>| matches!(result, Err(Error::InvalidInput { .. }));
+        assert!(too_large(set_custodian(product.id, bob())));
+        assert!(too_large(set_parent(product.id, Some(parent.id))));
+        set_caller(admin());
+        assert!(too_large(assign_owner(
+            product.id,
+            Principal::from_slice(&[7; 29])
+        )));
+        assert!(transfer_all_ownership(alice(), Principal::from_slice(&[7; 29])).is_err());
+        assert_eq!(owner_count(&alice()), 2);
+    }
+
+    #[test]
+    fn fuzzy_search_needs_a_minimum_query_length() {
+        setup();
+        add_as(alice(), "Tea");
+        assert!(matches!(
+            search_products_fuzzy(" ".to_string(), 3, 10, None),
+            Err(Error::InvalidInput { .. })
+        ));
+        let found = search_products_fuzzy("Tee".to_string(), 1, 10, None)
+            .ok()
+            .unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[test]
+    fn listings_leave_test_products_out_by_default() {
+        setup();
+        let real = add_as(alice(), "Coffee");
+        let mut sample = payload("Coffee sample");
+        sample.is_test = Some(true);
+        let sample = add_product(sample).ok().unwrap();
+
+        let ids = |products: Vec<Product>| -> Vec<u64> {
+            products.iter().map(|product| product.id).collect()
+        };
+        assert_eq!(ids(get_my_products_paginated(0, 10, None)), vec![real.id]);
+        assert_eq!(
+            ids(get_my_products_paginated(0, 10, Some(true))),
+            vec![real.id, sample.id]
+        );
+        assert_eq!(
+            ids(search_all_text("coffee".to_string(), 10, None)
+                .ok()
+                .unwrap()),
+            vec![real.id]
+        );
+        assert_eq!(
+            list_by_timestamp_after(None, None, 10, None)
+                .ok()
+                .unwrap()
+                .items
+                .len(),
+            1
+        );
+        assert_eq!(export_products_csv(None).lines().count(), 2);
+        assert_eq!(export_products_csv(Some(true)).lines().count(), 3);
+
+        set_caller(admin());
+        assert_eq!(
+            list_owners_with_counts(None).ok().unwrap(),
+            vec![(alice(), 1)]
+        );
+        assert_eq!(
+            list_owners_with_counts(Some(true)).ok().unwrap(),
+            vec![(alice(), 2)]
+        );
+        assert_eq!(owner_product_count(alice()), 2);
+    }
+
+    #[test]
+    fn imports_keep_ids_and_hierarchy_consistent() {
+        setup();
+        let stored = add_as(alice(), "Pallet");
+        let deleted = add_as(alice(), "Crate");
+        assert!(delete_product(deleted.id).is_ok());
+        let imported = |id: u64, parent_id: Option<u64>| {
+            let mut product = stored.clone();
+            product.id = id;
+            product.parent_id = parent_id;
+            product
+        };
+        let rejected = |products: Vec<Product>| {
+            set_caller(admin());
+            import_products_with_ids(products).is_err()
+        };
+
+        assert!(rejected(vec![imported(u64::MAX, None)]));
+        assert!(rejected(vec![imported(deleted.id, None)]));
+        assert!(rejected(vec![imported(10, Some(99))]));
+        assert!(rejected(vec![imported(10, Some(10))]));
+        assert!(rejected(vec![
+            imported(10, Some(11)),
+            imported(11, Some(10))
+        ]));
+        assert_eq!(PRODUCT_STORAGE.with(|storage| storage.borrow().len()), 1);
+
+        let batch = vec![imported(11, Some(10)), imported(10, Some(stored.id))];
+        assert_eq!(import_products_with_ids(batch).ok(), Some(2));
+        assert_eq!(ancestor_ids(11), vec![10, stored.id]);
+        assert!(get_ancestors(11).is_ok());
+        set_caller(alice());
+        assert_eq!(add_product(payload("Box")).ok().unwrap().id, 12);
+    }
+
+    #[test]
+    fn cascade_stamps_the_status_of_every_product() {
+        setup();
+        let parent = add_as(alice(), "Pallet");
+        let child = add_as(alice(), "Box");
+        assert!(set_parent(child.id, Some(parent.id)).is_ok());
+
+        advance_time(10);
+        assert_eq!(
+            update_status_cascade(parent.id, ProductStatus::InTransit).ok(),
+            Some(2)
+        );
+        for id in [parent.id, child.id] {
+            let stamps = get_field_history(id).ok().unwrap();
+            assert!(stamps.contains(&("status".to_string(), time())));
+        }
+    }
+
+    #[test]
+    fn undo_and_rollback_do_not_leave_a_terminal_status() {
+        setup();
+        let product = add_as(alice(), "Coffee");
+        let mut delivered = payload("Coffee");
+        delivered.status = ProductStatus::Delivered;
+        assert!(update_product(product.id, delivered).is_ok());
+
+        let refused =
+            |result: Result<Product, Error>| matches!(result, Err(Error::InvalidTransition { .. }));
+        assert!(refused(undo_last_update(product.id)));
+        assert!(refused(rollback_to_history_entry(product.id, 0)));
+        assert_eq!(
+            _get_product(&product.id).unwrap().status,
+            ProductStatus::Delivered
+        );
+        assert!(UNDO_SNAPSHOTS.with(|snapshots| snapshots.borrow().contains_key(&product.id)));
+    }
+
+    #[test]
+    fn validate_payload_checks_the_origin_allowlist() {
+        setup();
+        set_caller(admin());
+        assert!(add_allowed_origin("Ethiopia".to_string()).is_ok());
+        set_caller(alice());
+        assert!(matches!(
+            validate_payload(payload("Coffee")),
+            Err(Error::InvalidInput { .. })
+        ));
+        assert!(add_product(payload("Coffee")).is_err());
+
+        let mut allowed = payload("Coffee");
+        allowed.origin = "Ethiopia".to_string();
+        assert!(validate_payload(allowed.clone()).is_ok());
+        assert!(add_product(allowed).is_ok());
+    }
+
+    #[test]
+    fn migration_indexes_products_too_large_to_rewrite() {
+        setup();
+        // Fits without an owner, but not once the creator becomes the owner
+        let mut product = add_as(alice(), "Coffee");
+        product.owner = None;
+        product.created_by = Some(Principal::from_slice(&[7; 29]));
+        let mut space = Product::MAX_SIZE as usize - product.to_bytes().len();
+        loop {
+            product.iot_data = Some("x".repeat(space));
+            if check_size(&product).is_ok() {
+                break;
+            }
+            space -= 1;
+        }
+        let mut owned = product.clone();
+        owned.owner = owned.created_by;
+        assert!(check_size(&owned).is_err());
+        PRODUCT_STORAGE.with(|storage| storage.borrow_mut().insert(product.id, product.clone()));
+        TIMESTAMP_INDEX.with(|index| index.borrow_mut().remove(&(product.timestamp, product.id)));
+        set_stored_schema_version(2);
+
+        set_caller(admin());
+        assert_eq!(migrate_products().ok(), Some(0));
+        assert!(verify_timestamp_index());
+        let listed = list_by_timestamp_after(None, None, 10, None).ok().unwrap();
+        assert_eq!(listed.items.len(), 1);
+    }
+
+    #[test]
+    fn status_queries_accept_any_spelling() {
+        setup();
+        let mut delivered = payload("Coffee");
+        delivered.status = ProductStatus::Custom("delivered".to_string());
+        delivered.weight_grams = Some(500);
+        set_caller(alice());
+        let product = add_product(delivered).ok().unwrap();
+        assert_eq!(product.status, ProductStatus::Delivered);
+
+        let spelled = ProductStatus::Custom("Delivered".to_string());
+        let filter = ProductFilter {
+            status: Some(spelled.clone()),
+            ..Default::default()
+        };
+        assert!(matches_filter(&product, &filter));
+        assert_eq!(total_shipment_weight_by_status(spelled.clone(), None), 500);
+        assert_eq!(products_transitioned_to(spelled, 0, None).len(), 1);
+    }
+
+    #[test]
+    fn cascade_fails_when_the_named_product_is_too_large() {
+        setup();
+        let product = add_as(alice(), "Pallet");
+        let mut space = Product::MAX_SIZE as usize - product.to_bytes().len();
+        loop {
+            let mut full = payload("Pallet");
+            full.iot_data = Some("x".repeat(space));
+            if update_product(product.id, full).is_ok() {
+                break;
+            }
+            space -= 1;
+        }
+
+        let result = update_status_cascade(
+            product.id,
+            ProductStatus::Custom("Awaiting customs clearance".to_string()),
+        );
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+        assert_eq!(
+            _get_product(&product.id).unwrap().status,
+            ProductStatus::Manufactured
+        );
+    }
+
+    #[test]
+    fn diff_renders_every_field_as_plain_text() {
+        setup();
+        let mut certified = payload("Coffee");
+        certified.certification = Some("ISO-1".to_string());
+        certified.weight_grams = Some(500);
+        certified.dimensions_mm = Some((10, 20, 30));
+        set_caller(alice());
+        let a = add_product(certified).ok().unwrap();
+        let b = add_as(alice(), "Tea");
+
+        let diffs = diff_products(a.id, b.id).ok().unwrap();
+        let diff = |field: &str| {
+            let diff = diffs.iter().find(|diff| diff.field == field).unwrap();
+            (diff.a.clone(), diff.b.clone())
+        };
+        assert_eq!(diff("name"), ("Coffee".to_string(), "Tea".to_string()));
+        assert_eq!(diff("certification"), ("ISO-1".to_string(), String::new()));
+        assert_eq!(diff("weight_grams"), ("500".to_string(), String::new()));
+        assert_eq!(
+            diff("dimensions_mm"),
+            ("10x20x30".to_string(), String::new())
+        );
+    }
+
+    #[test]
+    fn raw_bytes_are_the_stored_ones() {
+        setup();
+        let product = add_as(alice(), "Coffee");
+        let old = InitialProduct {
+            id: product.id,
+            name: product.name.clone(),
+            origin: product.origin.clone(),
+            current_location: product.current_location.clone(),
+            status: "In Transit".to_string(),
+            certification: None,
+            timestamp: product.timestamp,
+            last_update: None,
+            iot_data: None,
+        };
+        let stored = Encode!(&old).unwrap();
+        raw_products().insert(product.id, RawProduct(stored.clone()));
+
+        set_caller(admin());
+        let raw = get_product_raw_bytes(product.id).ok().unwrap();
+        assert_eq!(raw, stored);
+        assert_ne!(
+            raw,
+            _get_product(&product.id).unwrap().to_bytes().into_owned()
+        );
+    }
+}